@@ -1,155 +1,96 @@
-use std::{fmt, net::SocketAddr, sync::Arc};
-
-use dashmap::DashMap;
-use futures::{stream::SplitStream, SinkExt, StreamExt};
-use tokio::{
-    net::{TcpListener, TcpStream},
-    sync::mpsc,
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
 };
-use tokio_util::codec::{Framed, LinesCodec};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use ecosystem::chat::{read_log, ChatServer, Config};
+use futures::future;
 use tracing::{info, level_filters::LevelFilter, warn};
 use tracing_subscriber::{
     fmt::Layer, layer::SubscriberExt as _, util::SubscriberInitExt as _, Layer as _,
 };
 
-const MAX_MESSAGES: usize = 128;
-
-#[derive(Debug, Default)]
-struct State {
-    peers: DashMap<SocketAddr, mpsc::Sender<Arc<Message>>>,
-}
-
-#[derive(Debug)]
-struct Peer {
-    username: String,
-    stream: SplitStream<Framed<TcpStream, LinesCodec>>,
-}
-
-#[derive(Debug)]
-enum Message {
-    UserJoined(String),
-    UserLeft(String),
-    Chat { sender: String, content: String },
-}
-
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    // chat export <from> <to> [config], with RFC 3339 timestamps
+    if let [cmd, from, to, rest @ ..] = args.as_slice() {
+        if cmd == "export" {
+            let config = resolve_config(rest.first())?;
+            let from = DateTime::parse_from_rfc3339(from)?.with_timezone(&Utc);
+            let to = DateTime::parse_from_rfc3339(to)?.with_timezone(&Utc);
+            return export(&config.log.dir, from, to);
+        }
+    }
+
     let layer = Layer::new().pretty().with_filter(LevelFilter::INFO);
     tracing_subscriber::registry().with(layer).init();
     // 连接tokio console
     // console_subscriber::init();
-    let addr = "0.0.0.0:8080";
-    let listener = TcpListener::bind(addr).await?;
-    info!("Start chat server on {}", addr);
-    let state = Arc::new(State::default());
-    loop {
-        let (stream, addr) = listener.accept().await?;
-        info!("Accept connection from {}", addr);
-        let state_cloned = state.clone();
-        tokio::spawn(async move {
-            if let Err(e) = handle_client(state_cloned, addr, stream).await {
-                warn!("Failed to handle connection from {}: {}", addr, e);
-            }
-        });
-    }
-}
+    let config = resolve_config(args.first())?;
+    let server = ChatServer::builder().config(config).build().await?;
 
-async fn handle_client(
-    state: Arc<State>,
-    addr: SocketAddr,
-    stream: TcpStream,
-) -> anyhow::Result<()> {
-    let mut stream = Framed::new(stream, LinesCodec::new());
-    stream.send("Enter your username:").await?;
-    let username = match stream.next().await {
-        Some(Ok(username)) => username,
-        Some(Err(e)) => return Err(e.into()),
-        None => return Ok(()),
-    };
-    let mut peer = state.add(addr, username, stream).await;
-    let message = Arc::new(Message::user_joined(&peer.username));
-    info!("{}", message);
-    state.broadcast(addr, message).await;
-    while let Some(line) = peer.stream.next().await {
-        let line = match line {
-            Ok(line) => line,
-            Err(e) => {
-                warn!("Failed to read line from {}: {}", addr, e);
-                break;
-            }
-        };
-        let message = Arc::new(Message::chat(&peer.username, line));
-        state.broadcast(addr, message).await;
-    }
-    state.peers.remove(&addr);
-    let message = Arc::new(Message::user_left(&peer.username));
-    info!("{}", message);
-    state.broadcast(addr, message).await;
-    Ok(())
+    let handle = server.handle();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        info!("Received shutdown signal");
+        handle.shutdown();
+    });
+    server.run().await
 }
 
-impl State {
-    async fn broadcast(&self, addr: SocketAddr, message: Arc<Message>) {
-        for peer in self.peers.iter() {
-            if peer.key() == &addr {
-                continue;
-            }
-            if let Err(e) = peer.value().send(message.clone()).await {
-                warn!("Failed to send message to {}: {}", peer.key(), e);
-
-                self.peers.remove(peer.key());
-            }
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Failed to listen for ctrl-c: {}", e);
+            future::pending::<()>().await;
         }
-    }
-    async fn add(
-        &self,
-        addr: SocketAddr,
-        username: String,
-        stream: Framed<TcpStream, LinesCodec>,
-    ) -> Peer {
-        let (tx, mut rx) = mpsc::channel(MAX_MESSAGES);
-        self.peers.insert(addr, tx);
-
-        let (mut stream_sender, straem_receiver) = stream.split();
-
-        tokio::spawn(async move {
-            while let Some(message) = rx.recv().await {
-                if let Err(e) = stream_sender.send(message.to_string()).await {
-                    warn!("Failed to send message to {}: {}", addr, e);
-                    break;
-                }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {}", e);
+                future::pending::<()>().await;
             }
-        });
-        Peer {
-            username,
-            stream: straem_receiver,
         }
+    };
+    #[cfg(not(unix))]
+    let terminate = future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
     }
 }
 
-impl Message {
-    fn user_joined(username: &str) -> Self {
-        let content = format!("{} joined the chat", username);
-        Self::UserJoined(content)
-    }
-    fn user_left(username: &str) -> Self {
-        let content = format!("{} left the chat", username);
-        Self::UserLeft(content)
-    }
-    fn chat(sender: impl Into<String>, content: impl Into<String>) -> Self {
-        Self::Chat {
-            sender: sender.into(),
-            content: content.into(),
-        }
-    }
+fn resolve_config(path: Option<&String>) -> anyhow::Result<Config> {
+    let Some(path) = path else {
+        // the library keeps nothing on disk unless told to, the example does
+        let mut config = Config::default();
+        config.log.enabled = true;
+        config.moderation.ban_file = Some(PathBuf::from("tmp/chat/bans.json"));
+        return Ok(config);
+    };
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Can not read config file: {}", path))?;
+    let config =
+        toml::from_str(&content).with_context(|| format!("Invalid config file: {}", path))?;
+    Ok(config)
 }
 
-impl fmt::Display for Message {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Self::UserJoined(content) => write!(f, "[{}]", content),
-            Self::UserLeft(content) => write!(f, "[{} :(]", content),
-            Self::Chat { sender, content } => write!(f, "{}: {}", sender, content),
+/// Print the logged events in `[from, to)` to stdout, one JSON record per line.
+fn export(dir: &Path, from: DateTime<Utc>, to: DateTime<Utc>) -> anyhow::Result<()> {
+    let mut stdout = std::io::stdout().lock();
+    for event in read_log(dir)? {
+        if event.timestamp >= from && event.timestamp < to {
+            writeln!(stdout, "{}", serde_json::to_string(&event)?)?;
         }
     }
+    Ok(())
 }
@@ -1,56 +1,2003 @@
-use std::sync::Arc;
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet, HashMap},
+    convert::Infallible,
+    fs,
+    future::Future,
+    hash::{Hash, Hasher},
+    io::{self, BufReader},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{self, ready, Poll},
+    time::SystemTime,
+};
 
+use anyhow::{anyhow, Context};
+use axum::body::Body;
+use futures::future;
+use hyper::{
+    body::{Body as _, Bytes, Frame, Incoming, SizeHint},
+    header::{self, HeaderMap, HeaderName, HeaderValue},
+    http::uri::Authority,
+    service::service_fn,
+    Request, Response, StatusCode, Uri, Version,
+};
+use hyper_util::{
+    client::legacy::{connect::Connected, Client},
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
-use tokio::net::{TcpListener, TcpStream};
-use tracing::{info, level_filters::LevelFilter, warn};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+    time::{Duration, Instant},
+};
+use tokio_rustls::{
+    client,
+    rustls::{
+        self,
+        crypto::ring::sign::any_supported_type,
+        pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+        RootCertStore,
+    },
+    server, TlsAcceptor, TlsConnector,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, level_filters::LevelFilter, warn, Level};
 use tracing_subscriber::{
-    fmt::Layer, layer::SubscriberExt as _, util::SubscriberInitExt as _, Layer as _,
+    filter::Targets, fmt::Layer, layer::SubscriberExt as _, util::SubscriberInitExt as _,
+    Layer as _,
 };
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
 
+// how often the config file is checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+// points per upstream on the consistent hash ring
+const VIRTUAL_NODES: usize = 160;
+// target of the access log entries, one per client connection
+const ACCESS_LOG: &str = "minginx::access";
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// pause before accepting again after a failure
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+// headers that only concern a single hop and are never forwarded
+const HOP_BY_HOP: [&str; 7] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+type HttpClient = Client<Connector, Body>;
+
+/// minginx <config.yaml|config.toml>
+///
+/// ```yaml
+/// upstreams:
+///   api:
+///     addrs: [127.0.0.1:8080, 127.0.0.1:8082]
+///     strategy: least_connections
+///     health_check:
+///       http_path: /health
+///     timeouts:
+///       connect_ms: 500
+///       idle_secs: 60
+///     retry:
+///       attempts: 2
+///   db:
+///     addrs: [db.internal:5432]
+///     tls:
+///       ca: certs/internal-ca.pem
+/// servers:
+///   - listen_addr: 0.0.0.0:8443
+///     mode: http
+///     tls:
+///       certs:
+///         - cert: certs/api.pem
+///           key: certs/api.key
+///     routes:
+///       - host: api.example.com
+///         path_prefix: /v1/
+///         rewrite: /
+///         upstream: api
+///   - listen_addr: 0.0.0.0:15432
+///     upstream: db
+/// ```
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
 struct Config {
-    listen_addr: String,
-    upstream_addr: String,
+    upstreams: BTreeMap<String, UpstreamConfig>,
+    servers: Vec<ServerConfig>,
+}
+
+/// A group of interchangeable upstreams.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct UpstreamConfig {
+    addrs: Vec<String>,
+    #[serde(default)]
+    strategy: Strategy,
+    #[serde(default)]
+    health_check: HealthCheckConfig,
+    #[serde(default)]
+    timeouts: TimeoutConfig,
+    #[serde(default)]
+    retry: RetryConfig,
+    // speak TLS to the upstreams
+    tls: Option<UpstreamTlsConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct ServerConfig {
+    listen_addr: SocketAddr,
+    #[serde(default)]
+    mode: Mode,
+    // where tcp servers send every connection
+    upstream: Option<String>,
+    // http servers send a request to the first route matching it
+    #[serde(default)]
+    routes: Vec<RouteConfig>,
+    // terminate TLS from clients
+    tls: Option<ListenerTlsConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Mode {
+    // copy bytes both ways
+    #[default]
+    Tcp,
+    // HTTP/1.1 or HTTP/2 from the client, HTTP/1.1 to the upstream
+    Http,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct RouteConfig {
+    // matches any host when missing, the port of the request is ignored
+    host: Option<String>,
+    // matched on whole path segments
+    #[serde(default = "default_path_prefix")]
+    path_prefix: String,
+    // replaces the matched prefix before the request is forwarded
+    rewrite: Option<String>,
+    upstream: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+struct HealthCheckConfig {
+    interval_secs: u64,
+    timeout_secs: u64,
+    // probes GET this path and expect a 2xx, without it a TCP connect will do
+    http_path: Option<String>,
+    // consecutive failed connects or probes that eject an upstream, a single
+    // successful probe re-admits it
+    max_failures: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+struct TimeoutConfig {
+    connect_ms: u64,
+    // no bytes either way for this long closes a client connection, in http
+    // mode waiting this long for the response head also gives a 504
+    idle_secs: u64,
+    // client connections are closed after this long no matter what, never
+    // when missing, http clients get the strictest limits of the groups
+    // their routes point to
+    max_lifetime_secs: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct ListenerTlsConfig {
+    // picked by the name a client asks for, the first one is the default
+    certs: Vec<CertConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct CertConfig {
+    // PEM chain, the names are read from the first certificate
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct UpstreamTlsConfig {
+    // PEM certificates that upstream certificates must chain to
+    ca: PathBuf,
+    // checked against the upstream certificates instead of the host of each
+    // address
+    server_name: Option<String>,
+}
+
+/// Failed connects are retried on another upstream when there is one.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+struct RetryConfig {
+    // connects per client, the first one included
+    attempts: u32,
+    // doubled after every failed connect
+    backoff_ms: u64,
+    max_backoff_ms: u64,
+}
+
+/// How a connection picks its upstream.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Strategy {
+    #[default]
+    RoundRobin,
+    LeastConnections,
+    Random,
+    // a client IP sticks to its upstream while the pool is unchanged
+    ConsistentHash,
+}
+
+/// The upstreams of a group.
+#[derive(Debug)]
+struct Pool {
+    config: UpstreamConfig,
+    upstreams: Vec<Arc<Upstream>>,
+    next: AtomicUsize,
+    // hash -> index of the upstream owning the point
+    ring: BTreeMap<u64, usize>,
+    tls: Option<Arc<UpstreamTls>>,
+    // connects the way `connect` does
+    client: HttpClient,
+}
+
+#[derive(Debug)]
+struct Upstream {
+    addr: String,
+    connections: AtomicUsize,
+    healthy: AtomicBool,
+    failures: AtomicU32,
+}
+
+/// Counts a proxied connection against its upstream while it is open.
+struct Connection(Arc<Upstream>);
+
+/// What new connections and requests are served with, replaced as a whole on
+/// every reload.
+#[derive(Debug, Default)]
+struct Snapshot {
+    servers: HashMap<SocketAddr, ServerConfig>,
+    pools: HashMap<String, Arc<Pool>>,
+    // listen address -> how TLS is terminated on it
+    tls: HashMap<SocketAddr, Arc<rustls::ServerConfig>>,
+}
+
+struct Proxy {
+    snapshot: watch::Sender<Arc<Snapshot>>,
+    // listen address -> stops accepting on it
+    listeners: HashMap<SocketAddr, CancellationToken>,
+    // upstream group -> stops its health checks
+    checks: HashMap<String, CancellationToken>,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let layer = Layer::new().pretty().with_filter(LevelFilter::INFO);
-    tracing_subscriber::registry().with(layer).init();
-    let config = resolve_config();
-    let config = Arc::new(config);
-    info!("Upstream address: {}", config.upstream_addr);
-    info!("Listen address: {}", config.listen_addr);
-
-    let listener = TcpListener::bind(&config.listen_addr).await?;
+    let console = Layer::new().pretty().with_filter(LevelFilter::INFO);
+    // access log entries also go to a daily file, one line each
+    let file_appender = tracing_appender::rolling::daily("tmp/logs", "minginx-access.log");
+    let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
+    let access = Layer::new()
+        .with_writer(non_blocking)
+        .with_ansi(false)
+        .with_filter(Targets::new().with_target(ACCESS_LOG, Level::INFO));
+    tracing_subscriber::registry()
+        .with(console)
+        .with(access)
+        .init();
+    let path = std::env::args()
+        .nth(1)
+        .map(PathBuf::from)
+        .ok_or_else(|| anyhow!("Usage: minginx <config.yaml|config.toml>"))?;
+    let mut version = file_version(&path)?;
+    let config = resolve_config(&path)?;
+
+    let mut proxy = Proxy::new();
+    proxy.apply(config).await?;
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+    // a config that failed to apply, e.g. on an address in use, is tried
+    // again on every tick until it succeeds or the file changes
+    let mut retry = false;
+    loop {
+        interval.tick().await;
+        match file_version(&path) {
+            Ok(current) if current == version && !retry => continue,
+            Ok(current) => version = current,
+            Err(e) => {
+                warn!("{:#}", e);
+                continue;
+            }
+        }
+        if !retry {
+            info!("Reloading {}", path.display());
+        }
+        // a broken config keeps the running one
+        let config = match resolve_config(&path) {
+            Ok(config) => config,
+            Err(e) => {
+                warn!("Failed to reload {}: {:#}", path.display(), e);
+                retry = false;
+                continue;
+            }
+        };
+        match proxy.apply(config).await {
+            Ok(()) => retry = false,
+            // warn once, not on every retry
+            Err(e) if !retry => {
+                warn!("Failed to reload {}: {:#}", path.display(), e);
+                retry = true;
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+impl Proxy {
+    fn new() -> Self {
+        Self {
+            snapshot: watch::Sender::new(Arc::default()),
+            listeners: HashMap::new(),
+            checks: HashMap::new(),
+        }
+    }
+
+    /// Bring the listeners and upstream groups in line with `config`: bind
+    /// new addresses and stop accepting on removed ones. Connections already
+    /// proxied are never touched, and a config that fails to apply leaves
+    /// the running one in place.
+    async fn apply(&mut self, config: Config) -> anyhow::Result<()> {
+        let previous = self.snapshot.borrow().clone();
+        let mut pools = HashMap::new();
+        for (name, upstream) in config.upstreams {
+            let pool = match previous.pools.get(&name) {
+                Some(pool) if pool.config == upstream => pool.clone(),
+                previous => {
+                    info!("Upstream group {} is {:?}", name, upstream.addrs);
+                    let pool = Pool::new(upstream, previous.map(|pool| &**pool))
+                        .with_context(|| format!("upstreams.{}", name))?;
+                    Arc::new(pool)
+                }
+            };
+            pools.insert(name, pool);
+        }
+        // certificates are loaded again on every reload
+        let mut tls = HashMap::new();
+        for (i, server) in config.servers.iter().enumerate() {
+            if let Some(config) = &server.tls {
+                let server_config = tls_server_config(config, server.mode)
+                    .with_context(|| format!("servers[{}].tls", i))?;
+                tls.insert(server.listen_addr, server_config);
+            }
+        }
+        let servers: HashMap<_, _> = config
+            .servers
+            .into_iter()
+            .map(|server| (server.listen_addr, server))
+            .collect();
+        // nothing is published unless every new address could be bound
+        let mut bound = Vec::new();
+        let mut failed = Vec::new();
+        for &addr in servers.keys() {
+            if self.listeners.contains_key(&addr) {
+                continue;
+            }
+            match TcpListener::bind(addr).await {
+                Ok(tcp) => bound.push((addr, tcp)),
+                Err(e) => failed.push(format!("{}: {}", addr, e)),
+            }
+        }
+        if !failed.is_empty() {
+            return Err(anyhow!("Can not bind {}", failed.join(", ")));
+        }
+        let snapshot = Arc::new(Snapshot {
+            servers,
+            pools,
+            tls,
+        });
+        self.snapshot.send_replace(snapshot.clone());
+
+        self.checks.retain(|name, shutdown| {
+            let keep = snapshot.pools.contains_key(name);
+            if !keep {
+                shutdown.cancel();
+            }
+            keep
+        });
+        for name in snapshot.pools.keys() {
+            if !self.checks.contains_key(name) {
+                let shutdown = CancellationToken::new();
+                let check = health_check(name.clone(), self.snapshot.subscribe(), shutdown.clone());
+                tokio::spawn(check);
+                self.checks.insert(name.clone(), shutdown);
+            }
+        }
+
+        self.listeners.retain(|addr, shutdown| {
+            if snapshot.servers.contains_key(addr) {
+                return true;
+            }
+            info!("Stop listening on {}", addr);
+            shutdown.cancel();
+            false
+        });
+        for (addr, tcp) in bound {
+            let server = &snapshot.servers[&addr];
+            let tls = if server.tls.is_some() {
+                " with TLS"
+            } else {
+                ""
+            };
+            info!("Listen on {} in {:?} mode{}", addr, server.mode, tls);
+            let shutdown = CancellationToken::new();
+            tokio::spawn(serve(tcp, self.snapshot.subscribe(), shutdown.clone()));
+            self.listeners.insert(addr, shutdown);
+        }
+        Ok(())
+    }
+}
+
+async fn serve(
+    listener: TcpListener,
+    snapshot: watch::Receiver<Arc<Snapshot>>,
+    shutdown: CancellationToken,
+) {
+    let Ok(listen_addr) = listener.local_addr() else {
+        return;
+    };
     loop {
-        let (client, addr) = listener.accept().await?;
-        let clone_config = config.clone();
+        let (stream, addr) = tokio::select! {
+            conn = listener.accept() => match conn {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("Failed to accept connection on {}: {}", listen_addr, e);
+                    tokio::select! {
+                        _ = tokio::time::sleep(ACCEPT_BACKOFF) => continue,
+                        _ = shutdown.cancelled() => break,
+                    }
+                }
+            },
+            _ = shutdown.cancelled() => break,
+        };
         info!("Accepted connection from: {}", addr);
+        let current = snapshot.borrow().clone();
+        let snapshot = snapshot.clone();
         tokio::spawn(async move {
-            let upstream = TcpStream::connect(&clone_config.upstream_addr).await?;
-            proxy(client, upstream).await?;
-            Ok::<(), anyhow::Error>(())
+            let traffic = Arc::new(Traffic::new());
+            let stream = Counted {
+                inner: stream,
+                traffic: traffic.clone(),
+            };
+            let Some(tls) = current.tls.get(&listen_addr) else {
+                return handle(stream, addr, listen_addr, &current, snapshot, traffic).await;
+            };
+            let accept = TlsAcceptor::from(tls.clone()).accept(stream);
+            match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, accept).await {
+                Ok(Ok(stream)) => {
+                    handle(stream, addr, listen_addr, &current, snapshot, traffic).await
+                }
+                Ok(Err(e)) => traffic.log(addr, "-", &format!("TLS handshake failed: {}", e)),
+                Err(_) => traffic.log(addr, "-", "TLS handshake timed out"),
+            }
         });
     }
-    #[allow(unreachable_code)]
-    Ok(())
 }
 
-async fn proxy(mut client: TcpStream, mut upstream: TcpStream) -> anyhow::Result<()> {
-    let (mut client_read, mut client_write) = client.split();
-    let (mut upstream_read, mut upstream_write) = upstream.split();
-    let client_to_upstream = tokio::io::copy(&mut client_read, &mut upstream_write);
-    let upstream_to_client = tokio::io::copy(&mut upstream_read, &mut client_write);
-    if let Err(e) = tokio::try_join!(client_to_upstream, upstream_to_client) {
-        warn!("Error proxying data: {:?}", e);
+/// Proxy a client connection the way its server is configured.
+async fn handle(
+    mut client: impl ClientStream,
+    addr: SocketAddr,
+    listen_addr: SocketAddr,
+    current: &Snapshot,
+    snapshot: watch::Receiver<Arc<Snapshot>>,
+    traffic: Arc<Traffic>,
+) {
+    let Some(server) = current.servers.get(&listen_addr) else {
+        return;
     };
+    if server.mode == Mode::Http {
+        let tls = server.tls.is_some();
+        // the strictest limits of the upstream groups the client may reach
+        let pools: Vec<_> = server
+            .routes
+            .iter()
+            .filter_map(|route| current.pools.get(&route.upstream))
+            .collect();
+        let timeouts = TimeoutConfig {
+            idle_secs: pools
+                .iter()
+                .map(|pool| pool.config.timeouts.idle_secs)
+                .min()
+                .unwrap_or(TimeoutConfig::default().idle_secs),
+            max_lifetime_secs: pools
+                .iter()
+                .filter_map(|pool| pool.config.timeouts.max_lifetime_secs)
+                .min(),
+            ..Default::default()
+        };
+        return serve_http(client, addr, listen_addr, snapshot, traffic, &timeouts, tls).await;
+    }
+    let Some(pool) = server
+        .upstream
+        .as_ref()
+        .and_then(|upstream| current.pools.get(upstream))
+    else {
+        return;
+    };
+    let (connection, mut upstream) = match pool.connect(addr.ip()).await {
+        Ok(connected) => connected,
+        Err(e) => {
+            warn!("Rejecting {} on {}: {}", addr, listen_addr, e);
+            // reset the connection so that the client fails right away
+            let _ = socket2::SockRef::from(client.tcp()).set_linger(Some(Duration::ZERO));
+            traffic.log(addr, "-", &e.to_string());
+            return;
+        }
+    };
+    let timeouts = &pool.config.timeouts;
+    let reason = match proxy(&mut client, &mut upstream, &traffic, timeouts).await {
+        Ok(()) => "closed".to_string(),
+        Err(e) => e.to_string(),
+    };
+    traffic.log(addr, &connection.0.addr, &reason);
+}
+
+/// Serve the requests of an HTTP client, each with the latest config.
+async fn serve_http(
+    client: impl ClientStream,
+    addr: SocketAddr,
+    listen_addr: SocketAddr,
+    snapshot: watch::Receiver<Arc<Snapshot>>,
+    traffic: Arc<Traffic>,
+    timeouts: &TimeoutConfig,
+    tls: bool,
+) {
+    // upstreams that served the requests of the connection
+    let upstreams = Arc::new(Mutex::new(BTreeSet::new()));
+    let service = service_fn({
+        let upstreams = upstreams.clone();
+        let traffic = traffic.clone();
+        move |req| {
+            let snapshot = snapshot.borrow().clone();
+            let upstreams = upstreams.clone();
+            let traffic = traffic.clone();
+            async move {
+                traffic.waiting.fetch_add(1, Ordering::Relaxed);
+                let response = forward(req, addr, listen_addr, tls, &snapshot, &upstreams).await;
+                traffic.touch();
+                traffic.waiting.fetch_sub(1, Ordering::Relaxed);
+                Ok::<_, Infallible>(response)
+            }
+        }
+    });
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let reason = tokio::select! {
+        // keep-alive connections are closed once idle like tcp ones
+        served = builder.serve_connection(TokioIo::new(client), service) => match served {
+            Ok(()) => "closed".to_string(),
+            Err(e) => e.to_string(),
+        },
+        e = timeouts.exceeded(&traffic) => e.to_string(),
+    };
+    let upstreams: Vec<_> = upstreams.lock().unwrap().iter().cloned().collect();
+    let upstreams = if upstreams.is_empty() {
+        "-".to_string()
+    } else {
+        upstreams.join(",")
+    };
+    traffic.log(addr, &upstreams, &reason);
+}
+
+/// Send a request to an upstream of its route and relay the response.
+async fn forward(
+    req: Request<Incoming>,
+    client_addr: SocketAddr,
+    listen_addr: SocketAddr,
+    tls: bool,
+    snapshot: &Snapshot,
+    upstreams: &Mutex<BTreeSet<String>>,
+) -> Response<Body> {
+    // HTTP/2 requests carry the host in the URI instead of a header
+    let authority = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.parse::<Authority>().ok())
+        .or_else(|| req.uri().authority().cloned());
+    let host = authority.as_ref().map(|authority| authority.host());
+    let route = snapshot.servers.get(&listen_addr).and_then(|server| {
+        server
+            .routes
+            .iter()
+            .find(|route| route.matches(host, req.uri().path()))
+    });
+    let Some(route) = route else {
+        return status(StatusCode::NOT_FOUND);
+    };
+    let Some(pool) = snapshot.pools.get(&route.upstream) else {
+        return status(StatusCode::SERVICE_UNAVAILABLE);
+    };
+
+    let (mut parts, body) = req.into_parts();
+    let path = route.rewrite(&parts.uri);
+    let headers = &mut parts.headers;
+    strip_hop_by_hop(headers);
+    if let Some(authority) = &authority {
+        if let Ok(value) = HeaderValue::from_str(authority.as_str()) {
+            headers.insert(header::HOST, value.clone());
+            headers.insert("x-forwarded-host", value);
+        }
+    }
+    // append to what proxies in front of this one have added
+    let forwarded_for = match headers
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(previous) => format!("{}, {}", previous, client_addr.ip()),
+        None => client_addr.ip().to_string(),
+    };
+    if let Ok(value) = HeaderValue::from_str(&forwarded_for) {
+        headers.insert("x-forwarded-for", value);
+    }
+    let proto = if tls { "https" } else { "http" };
+    headers.insert("x-forwarded-proto", HeaderValue::from_static(proto));
+
+    // only a request without a body can be sent again after a failed connect
+    let replayable = body.is_end_stream();
+    let mut body = Some(Body::new(body));
+    let max_failures = pool.config.health_check.max_failures;
+    let idle = Duration::from_secs(pool.config.timeouts.idle_secs);
+    let mut tried = Vec::new();
+    loop {
+        let Some(connection) = pool.pick(client_addr.ip(), &tried) else {
+            warn!(
+                "No healthy upstream in {} for {}",
+                route.upstream, client_addr
+            );
+            return status(StatusCode::SERVICE_UNAVAILABLE);
+        };
+        let uri = format!("http://{}{}", connection.0.addr, path);
+        let mut req = Request::new(body.take().unwrap_or_default());
+        match uri.parse() {
+            Ok(uri) => *req.uri_mut() = uri,
+            Err(e) => {
+                warn!("Invalid upstream URI {}: {}", uri, e);
+                return status(StatusCode::BAD_GATEWAY);
+            }
+        }
+        *req.method_mut() = parts.method.clone();
+        *req.version_mut() = Version::HTTP_11;
+        *req.headers_mut() = parts.headers.clone();
+
+        let e = match tokio::time::timeout(idle, pool.client.request(req)).await {
+            Ok(Ok(mut response)) => {
+                connection.0.report(true, max_failures);
+                upstreams.lock().unwrap().insert(connection.0.addr.clone());
+                strip_hop_by_hop(response.headers_mut());
+                // the upstream stays busy until the whole body is relayed
+                return response.map(|body| {
+                    Body::new(Streaming {
+                        body,
+                        _connection: connection,
+                    })
+                });
+            }
+            Ok(Err(e)) => e,
+            Err(_) => {
+                warn!("Upstream {} timed out", connection.0.addr);
+                return status(StatusCode::GATEWAY_TIMEOUT);
+            }
+        };
+        warn!("Failed to forward to upstream {}: {}", connection.0.addr, e);
+        if !e.is_connect() {
+            return status(StatusCode::BAD_GATEWAY);
+        }
+        connection.0.report(false, max_failures);
+        tried.push(connection.0.addr.clone());
+        if !replayable || tried.len() >= pool.config.retry.attempts as usize {
+            return status(StatusCode::BAD_GATEWAY);
+        }
+        tokio::time::sleep(pool.config.retry.backoff(tried.len())).await;
+    }
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::from(code.to_string()));
+    *response.status_mut() = code;
+    response
+}
+
+/// A response body that holds on to the upstream it streams from.
+struct Streaming {
+    body: Incoming,
+    _connection: Connection,
+}
+
+impl hyper::body::Body for Streaming {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, hyper::Error>>> {
+        Pin::new(&mut self.body).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    // headers listed in Connection are hop-by-hop as well
+    let listed: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| name.trim().parse().ok())
+        .collect();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(name);
+    }
+}
+
+impl RouteConfig {
+    fn matches(&self, host: Option<&str>, path: &str) -> bool {
+        let host_matches = match &self.host {
+            Some(expected) => host.is_some_and(|host| host.eq_ignore_ascii_case(expected)),
+            None => true,
+        };
+        // /v1 matches /v1 and /v1/users, not /v1beta
+        let path_matches = path
+            .strip_prefix(self.path_prefix.as_str())
+            .is_some_and(|rest| {
+                rest.is_empty() || rest.starts_with('/') || self.path_prefix.ends_with('/')
+            });
+        host_matches && path_matches
+    }
+
+    /// The path and query to request from the upstream.
+    fn rewrite(&self, uri: &Uri) -> String {
+        let path = uri.path();
+        let mut rewritten = match &self.rewrite {
+            Some(rewrite) => {
+                let rest = &path[self.path_prefix.len()..];
+                // exactly one slash where the two meet
+                match (rewrite.ends_with('/'), rest.starts_with('/')) {
+                    (true, true) => format!("{}{}", rewrite, &rest[1..]),
+                    (false, false) if !rest.is_empty() => format!("{}/{}", rewrite, rest),
+                    _ => format!("{}{}", rewrite, rest),
+                }
+            }
+            None => path.to_string(),
+        };
+        if let Some(query) = uri.query() {
+            rewritten.push('?');
+            rewritten.push_str(query);
+        }
+        rewritten
+    }
+}
+
+fn default_path_prefix() -> String {
+    "/".to_string()
+}
+
+impl Pool {
+    /// Upstreams that are also in `previous` keep their state.
+    fn new(config: UpstreamConfig, previous: Option<&Pool>) -> anyhow::Result<Self> {
+        let upstreams: Vec<_> = config
+            .addrs
+            .iter()
+            .map(|addr| {
+                previous
+                    .and_then(|pool| pool.upstreams.iter().find(|u| &u.addr == addr))
+                    .cloned()
+                    .unwrap_or_else(|| {
+                        Arc::new(Upstream {
+                            addr: addr.clone(),
+                            connections: AtomicUsize::new(0),
+                            healthy: AtomicBool::new(true),
+                            failures: AtomicU32::new(0),
+                        })
+                    })
+            })
+            .collect();
+        let mut ring = BTreeMap::new();
+        if config.strategy == Strategy::ConsistentHash {
+            for (i, upstream) in upstreams.iter().enumerate() {
+                for node in 0..VIRTUAL_NODES {
+                    ring.insert(hash(&(&upstream.addr, node)), i);
+                }
+            }
+        }
+        let tls = match &config.tls {
+            Some(tls) => Some(Arc::new(UpstreamTls::new(tls).context("tls")?)),
+            None => None,
+        };
+        let connector = Connector {
+            timeout: Duration::from_millis(config.timeouts.connect_ms),
+            tls: tls.clone(),
+        };
+        Ok(Self {
+            config,
+            upstreams,
+            next: AtomicUsize::new(0),
+            ring,
+            tls,
+            client: Client::builder(TokioExecutor::new()).build(connector),
+        })
+    }
+
+    /// Pick a healthy upstream, `None` when all of them are down. Upstreams
+    /// not in `tried` are preferred.
+    fn pick(&self, client: IpAddr, tried: &[String]) -> Option<Connection> {
+        let len = self.upstreams.len();
+        let healthy = |i: &usize| {
+            let upstream = &self.upstreams[*i];
+            upstream.healthy.load(Ordering::Relaxed) && !tried.contains(&upstream.addr)
+        };
+        let i = match self.config.strategy {
+            Strategy::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..len).map(|offset| (start + offset) % len).find(healthy)
+            }
+            Strategy::LeastConnections => {
+                // start at a rotating offset so that ties are spread out
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..len)
+                    .map(|offset| (start + offset) % len)
+                    .filter(healthy)
+                    .min_by_key(|&i| self.upstreams[i].connections.load(Ordering::Relaxed))
+            }
+            Strategy::Random => {
+                let candidates: Vec<_> = (0..len).filter(healthy).collect();
+                (!candidates.is_empty())
+                    .then(|| candidates[rand::thread_rng().gen_range(0..candidates.len())])
+            }
+            Strategy::ConsistentHash => {
+                // the next healthy owner clockwise takes over for a dead one
+                let point = hash(&client);
+                self.ring
+                    .range(point..)
+                    .chain(self.ring.range(..point))
+                    .map(|(_, &i)| i)
+                    .find(healthy)
+            }
+        };
+        let Some(i) = i else {
+            return (!tried.is_empty())
+                .then(|| self.pick(client, &[]))
+                .flatten();
+        };
+        let upstream = self.upstreams[i].clone();
+        upstream.connections.fetch_add(1, Ordering::Relaxed);
+        Some(Connection(upstream))
+    }
+
+    /// Connect to an upstream, retrying on others with a backoff.
+    async fn connect(&self, client: IpAddr) -> anyhow::Result<(Connection, UpstreamStream)> {
+        let timeout = Duration::from_millis(self.config.timeouts.connect_ms);
+        let max_failures = self.config.health_check.max_failures;
+        let mut tried = Vec::new();
+        loop {
+            let connection = self
+                .pick(client, &tried)
+                .ok_or_else(|| anyhow!("No healthy upstream"))?;
+            let addr = &connection.0.addr;
+            let e = match connect_upstream(addr, timeout, self.tls.as_deref()).await {
+                Ok(stream) => {
+                    connection.0.report(true, max_failures);
+                    return Ok((connection, stream));
+                }
+                Err(e) => e,
+            };
+            warn!("Failed to connect to upstream {}: {}", addr, e);
+            connection.0.report(false, max_failures);
+            tried.push(addr.clone());
+            if tried.len() >= self.config.retry.attempts as usize {
+                return Err(anyhow!("Gave up after {} connects: {}", tried.len(), e));
+            }
+            tokio::time::sleep(self.config.retry.backoff(tried.len())).await;
+        }
+    }
+}
+
+impl Upstream {
+    /// Record the outcome of a connect or a probe.
+    fn report(&self, ok: bool, max_failures: u32) {
+        if ok {
+            self.failures.store(0, Ordering::Relaxed);
+            if !self.healthy.swap(true, Ordering::Relaxed) {
+                info!("Upstream {} is healthy again", self.addr);
+            }
+            return;
+        }
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= max_failures && self.healthy.swap(false, Ordering::Relaxed) {
+            warn!(
+                "Upstream {} failed {} times, ejecting it",
+                self.addr, failures
+            );
+        }
+    }
+
+    async fn probe(
+        &self,
+        config: &HealthCheckConfig,
+        tls: Option<&UpstreamTls>,
+    ) -> anyhow::Result<()> {
+        let timeout = Duration::from_secs(config.timeout_secs);
+        let mut stream = connect_upstream(&self.addr, timeout, tls).await?;
+        let Some(path) = &config.http_path else {
+            return Ok(());
+        };
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            path, self.addr
+        );
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        // the status line is all we need
+        while !response.contains(&b'\n') {
+            let mut buf = [0; 256];
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                return Err(anyhow!("Connection closed before the status line"));
+            }
+            response.extend_from_slice(&buf[..n]);
+        }
+        let status_line = String::from_utf8_lossy(&response);
+        let status = status_line.split_whitespace().nth(1).unwrap_or_default();
+        if !status.starts_with('2') || status.len() != 3 {
+            return Err(anyhow!("Unexpected status {:?}", status));
+        }
+        Ok(())
+    }
+}
+
+/// Probe the upstreams of a group until it is removed.
+async fn health_check(
+    name: String,
+    snapshot: watch::Receiver<Arc<Snapshot>>,
+    shutdown: CancellationToken,
+) {
+    loop {
+        let Some(pool) = snapshot.borrow().pools.get(&name).cloned() else {
+            return;
+        };
+        let config = &pool.config.health_check;
+        let timeout = Duration::from_secs(config.timeout_secs);
+        let tls = pool.tls.as_deref();
+        let probes = pool.upstreams.iter().map(|upstream| async move {
+            let result = match tokio::time::timeout(timeout, upstream.probe(config, tls)).await {
+                Ok(result) => result,
+                Err(_) => Err(anyhow!("Timed out")),
+            };
+            if let Err(e) = &result {
+                warn!("Health check of upstream {} failed: {}", upstream.addr, e);
+            }
+            upstream.report(result.is_ok(), config.max_failures);
+        });
+        future::join_all(probes).await;
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(config.interval_secs)) => {}
+            _ = shutdown.cancelled() => return,
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+async fn proxy(
+    client: &mut impl ClientStream,
+    upstream: &mut UpstreamStream,
+    traffic: &Traffic,
+    timeouts: &TimeoutConfig,
+) -> anyhow::Result<()> {
+    tokio::select! {
+        // passes half closes on, so the connection ends once both sides are done
+        result = tokio::io::copy_bidirectional(client, upstream) => {
+            result?;
+            Ok(())
+        }
+        e = timeouts.exceeded(traffic) => Err(e),
+    }
+}
+
+impl TimeoutConfig {
+    /// Resolves with the reason once the connection was idle or open for too
+    /// long.
+    async fn exceeded(&self, traffic: &Traffic) -> anyhow::Error {
+        let idle = Duration::from_secs(self.idle_secs);
+        let lifetime = async {
+            match self.max_lifetime_secs {
+                Some(secs) => tokio::time::sleep(Duration::from_secs(secs)).await,
+                None => future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = traffic.idle(idle) => anyhow!("Idle for {:?}", idle),
+            _ = lifetime => anyhow!("Reached the max lifetime"),
+        }
+    }
+}
+
+/// What went through a client connection.
+#[derive(Debug)]
+struct Traffic {
+    start: Instant,
+    // millis since `start` when bytes last went through, in either direction
+    last: AtomicU64,
+    received: AtomicU64,
+    sent: AtomicU64,
+    // http requests waiting for their upstream, which has its own timeout
+    waiting: AtomicU64,
+}
+
+impl Traffic {
+    fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+            waiting: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, counter: &AtomicU64, n: usize) {
+        if n == 0 {
+            return;
+        }
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+        self.touch();
+    }
+
+    fn touch(&self) {
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.last.store(elapsed, Ordering::Relaxed);
+    }
+
+    /// Resolves once nothing went through for `timeout`.
+    async fn idle(&self, timeout: Duration) {
+        loop {
+            if self.waiting.load(Ordering::Relaxed) > 0 {
+                self.touch();
+            }
+            let last = self.start + Duration::from_millis(self.last.load(Ordering::Relaxed));
+            if last + timeout <= Instant::now() {
+                return;
+            }
+            tokio::time::sleep_until(last + timeout).await;
+        }
+    }
+
+    /// Write the access log entry of the connection.
+    fn log(&self, client: SocketAddr, upstream: &str, reason: &str) {
+        info!(
+            target: ACCESS_LOG,
+            client = %client,
+            upstream,
+            bytes_in = self.received.load(Ordering::Relaxed),
+            bytes_out = self.sent.load(Ordering::Relaxed),
+            duration_ms = self.start.elapsed().as_millis() as u64,
+            reason,
+            "Connection closed"
+        );
+    }
+}
+
+/// A client stream that records its [`Traffic`].
+struct Counted<S> {
+    inner: S,
+    traffic: Arc<Traffic>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = ready!(Pin::new(&mut self.inner).poll_read(cx, buf));
+        let n = buf.filled().len() - before;
+        self.traffic.record(&self.traffic.received, n);
+        Poll::Ready(result)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let n = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.traffic.record(&self.traffic.sent, n);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// A client connection, with or without TLS.
+trait ClientStream: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    fn tcp(&self) -> &TcpStream;
+}
+
+impl ClientStream for Counted<TcpStream> {
+    fn tcp(&self) -> &TcpStream {
+        &self.inner
+    }
+}
+
+impl ClientStream for server::TlsStream<Counted<TcpStream>> {
+    fn tcp(&self) -> &TcpStream {
+        &self.get_ref().0.inner
+    }
+}
+
+/// A connection to an upstream, with or without TLS.
+enum UpstreamStream {
+    Plain(TcpStream),
+    Tls(Box<client::TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for UpstreamStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for UpstreamStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+impl hyper_util::client::legacy::connect::Connection for UpstreamStream {
+    fn connected(&self) -> Connected {
+        Connected::new()
+    }
+}
+
+/// How the upstreams of a group are spoken to over TLS.
+#[derive(Debug)]
+struct UpstreamTls {
+    config: Arc<rustls::ClientConfig>,
+    // the host of each upstream address when missing
+    server_name: Option<ServerName<'static>>,
+}
+
+impl UpstreamTls {
+    fn new(config: &UpstreamTlsConfig) -> anyhow::Result<Self> {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(&config.ca)? {
+            roots.add(cert)?;
+        }
+        let client_config = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let server_name = match &config.server_name {
+            Some(name) => Some(ServerName::try_from(name.clone())?),
+            None => None,
+        };
+        Ok(Self {
+            config: Arc::new(client_config),
+            server_name,
+        })
+    }
+}
+
+/// Connect to an upstream, the TLS handshake included in the timeout.
+async fn connect_upstream(
+    addr: &str,
+    timeout: Duration,
+    tls: Option<&UpstreamTls>,
+) -> io::Result<UpstreamStream> {
+    let connect = async {
+        let stream = TcpStream::connect(addr).await?;
+        let Some(tls) = tls else {
+            return Ok(UpstreamStream::Plain(stream));
+        };
+        let server_name = match &tls.server_name {
+            Some(name) => name.clone(),
+            None => host_name(addr).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+        };
+        let connector = TlsConnector::from(tls.config.clone());
+        let stream = connector.connect(server_name, stream).await?;
+        Ok(UpstreamStream::Tls(Box::new(stream)))
+    };
+    match tokio::time::timeout(timeout, connect).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("Timed out after {:?}", timeout),
+        )),
+    }
+}
+
+/// Connects the HTTP client of a group the way [`Pool::connect`] does.
+#[derive(Debug, Clone)]
+struct Connector {
+    timeout: Duration,
+    tls: Option<Arc<UpstreamTls>>,
+}
+
+impl tower_service::Service<Uri> for Connector {
+    type Response = TokioIo<UpstreamStream>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connector = self.clone();
+        Box::pin(async move {
+            let addr = uri
+                .authority()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No upstream address"))?
+                .to_string();
+            let stream =
+                connect_upstream(&addr, connector.timeout, connector.tls.as_deref()).await?;
+            Ok(TokioIo::new(stream))
+        })
+    }
+}
+
+/// Picks the certificate for the name a client asked for, the first one when
+/// the name is missing or unknown.
+#[derive(Debug)]
+struct SniResolver {
+    // lowercase DNS name, `*.` for wildcards -> certificate
+    names: HashMap<String, Arc<CertifiedKey>>,
+    default: Arc<CertifiedKey>,
+}
+
+impl SniResolver {
+    fn lookup(&self, name: Option<&str>) -> &Arc<CertifiedKey> {
+        let Some(name) = name else {
+            return &self.default;
+        };
+        let name = name.to_ascii_lowercase();
+        let wildcard = name
+            .split_once('.')
+            .map(|(_, parent)| format!("*.{}", parent));
+        self.names
+            .get(&name)
+            .or_else(|| wildcard.and_then(|wildcard| self.names.get(&wildcard)))
+            .unwrap_or(&self.default)
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.lookup(hello.server_name()).clone())
+    }
+}
+
+/// Load the certificates of a listener, each is picked by the names it is
+/// issued for.
+fn tls_server_config(
+    config: &ListenerTlsConfig,
+    mode: Mode,
+) -> anyhow::Result<Arc<rustls::ServerConfig>> {
+    let mut names = HashMap::new();
+    let mut default = None;
+    for (i, cert) in config.certs.iter().enumerate() {
+        let chain = load_certs(&cert.cert).with_context(|| format!("certs[{}]", i))?;
+        let key = load_key(&cert.key).with_context(|| format!("certs[{}]", i))?;
+        let leaf = chain
+            .first()
+            .ok_or_else(|| anyhow!("certs[{}]: no certificate in {}", i, cert.cert.display()))?;
+        let issued_for = certificate_names(leaf).with_context(|| format!("certs[{}]", i))?;
+        let key = any_supported_type(&key).with_context(|| format!("certs[{}]", i))?;
+        let certified = Arc::new(CertifiedKey::new(chain, key));
+        for name in issued_for {
+            // an earlier certificate wins a name
+            names.entry(name).or_insert_with(|| certified.clone());
+        }
+        default.get_or_insert(certified);
+    }
+    let default = default.ok_or_else(|| anyhow!("certs: at least one is required"))?;
+    let mut server_config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(SniResolver { names, default }));
+    if mode == Mode::Http {
+        server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    }
+    Ok(Arc::new(server_config))
+}
+
+// the DNS names of the subject alternative names, the common name without them
+fn certificate_names(cert: &CertificateDer<'_>) -> anyhow::Result<Vec<String>> {
+    let (_, cert) = X509Certificate::from_der(cert.as_ref())?;
+    let mut names = Vec::new();
+    if let Some(san) = cert.subject_alternative_name()? {
+        for name in &san.value.general_names {
+            if let GeneralName::DNSName(name) = name {
+                names.push(name.to_ascii_lowercase());
+            }
+        }
+    }
+    if names.is_empty() {
+        if let Some(cn) = cert.subject().iter_common_name().next() {
+            names.push(cn.as_str()?.to_ascii_lowercase());
+        }
+    }
+    Ok(names)
+}
+
+fn load_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let file = fs::File::open(path)
+        .with_context(|| format!("Can not open certificate file: {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file)).collect::<Result<_, _>>()?;
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> anyhow::Result<PrivateKeyDer<'static>> {
+    let file = fs::File::open(path)
+        .with_context(|| format!("Can not open key file: {}", path.display()))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))?
+        .ok_or_else(|| anyhow!("No private key found in {}", path.display()))
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: 5,
+            timeout_secs: 2,
+            http_path: None,
+            max_failures: 3,
+        }
+    }
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            connect_ms: 3000,
+            idle_secs: 600,
+            max_lifetime_secs: None,
+        }
+    }
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            backoff_ms: 50,
+            max_backoff_ms: 1000,
+        }
+    }
+}
+
+impl RetryConfig {
+    /// How long to wait after the `failures`th failed connect.
+    fn backoff(&self, failures: usize) -> Duration {
+        let factor = 1u64 << failures.saturating_sub(1).min(16);
+        let millis = self.backoff_ms.saturating_mul(factor);
+        Duration::from_millis(millis.min(self.max_backoff_ms))
+    }
+}
+
+/// Changes whenever the file is rewritten or replaced.
+fn file_version(path: &Path) -> anyhow::Result<(SystemTime, u64)> {
+    let metadata = fs::metadata(path)
+        .with_context(|| format!("Can not read config file: {}", path.display()))?;
+    Ok((metadata.modified()?, metadata.len()))
+}
+
+fn resolve_config(path: &Path) -> anyhow::Result<Config> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Can not read config file: {}", path.display()))?;
+    let config: Config = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("yaml" | "yml") => serde_yaml::from_str(&content)
+            .with_context(|| format!("Invalid config file: {}", path.display()))?,
+        Some("toml") => toml::from_str(&content)
+            .with_context(|| format!("Invalid config file: {}", path.display()))?,
+        _ => {
+            return Err(anyhow!(
+                "Config file must end in .yaml, .yml or .toml: {}",
+                path.display()
+            ))
+        }
+    };
+    config
+        .validate()
+        .with_context(|| format!("Invalid config file: {}", path.display()))?;
+    Ok(config)
+}
+
+impl Config {
+    fn validate(&self) -> anyhow::Result<()> {
+        for (name, upstream) in &self.upstreams {
+            upstream
+                .validate()
+                .with_context(|| format!("upstreams.{}", name))?;
+        }
+        if self.servers.is_empty() {
+            return Err(anyhow!("servers: at least one server is required"));
+        }
+        for (i, server) in self.servers.iter().enumerate() {
+            if self.servers[..i]
+                .iter()
+                .any(|other| other.listen_addr == server.listen_addr)
+            {
+                return Err(anyhow!(
+                    "servers[{}].listen_addr: {} is used more than once",
+                    i,
+                    server.listen_addr
+                ));
+            }
+            self.validate_server(server)
+                .with_context(|| format!("servers[{}]", i))?;
+        }
+        Ok(())
+    }
+
+    fn validate_server(&self, server: &ServerConfig) -> anyhow::Result<()> {
+        let known = |upstream: &str| {
+            if self.upstreams.contains_key(upstream) {
+                Ok(())
+            } else {
+                Err(anyhow!("unknown upstream group {:?}", upstream))
+            }
+        };
+        match server.mode {
+            Mode::Tcp => {
+                if !server.routes.is_empty() {
+                    return Err(anyhow!("routes: only http servers have routes"));
+                }
+                let upstream = server
+                    .upstream
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("upstream: required in tcp mode"))?;
+                known(upstream).context("upstream")?;
+            }
+            Mode::Http => {
+                if server.upstream.is_some() {
+                    return Err(anyhow!("upstream: http servers pick it with routes"));
+                }
+                if server.routes.is_empty() {
+                    return Err(anyhow!("routes: at least one is required in http mode"));
+                }
+                for (i, route) in server.routes.iter().enumerate() {
+                    if !route.path_prefix.starts_with('/') {
+                        return Err(anyhow!("routes[{}].path_prefix: must start with /", i));
+                    }
+                    if route.rewrite.as_ref().is_some_and(|r| !r.starts_with('/')) {
+                        return Err(anyhow!("routes[{}].rewrite: must start with /", i));
+                    }
+                    known(&route.upstream).with_context(|| format!("routes[{}].upstream", i))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl UpstreamConfig {
+    fn validate(&self) -> anyhow::Result<()> {
+        let health_check = &self.health_check;
+        if health_check.interval_secs == 0 || health_check.max_failures == 0 {
+            return Err(anyhow!(
+                "health_check: interval_secs and max_failures must be positive"
+            ));
+        }
+        let timeouts = &self.timeouts;
+        if timeouts.connect_ms == 0
+            || timeouts.idle_secs == 0
+            || timeouts.max_lifetime_secs == Some(0)
+        {
+            return Err(anyhow!("timeouts: must be positive"));
+        }
+        if self.retry.attempts == 0 {
+            return Err(anyhow!("retry.attempts: at least one is required"));
+        }
+        if let Some(tls) = &self.tls {
+            match &tls.server_name {
+                Some(name) => {
+                    ServerName::try_from(name.as_str()).context("tls.server_name")?;
+                }
+                None => {
+                    for (i, addr) in self.addrs.iter().enumerate() {
+                        host_name(addr).with_context(|| format!("addrs[{}]", i))?;
+                    }
+                }
+            }
+        }
+        if self.addrs.is_empty() {
+            return Err(anyhow!("addrs: at least one is required"));
+        }
+        for (i, addr) in self.addrs.iter().enumerate() {
+            validate_addr(addr).with_context(|| format!("addrs[{}]", i))?;
+            if self.addrs[..i].contains(addr) {
+                return Err(anyhow!("addrs[{}]: {} is listed more than once", i, addr));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn hash(value: &impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+// host:port, the host is resolved on every connect
+fn validate_addr(addr: &str) -> anyhow::Result<()> {
+    let (host, port) = addr
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow!("{:?} is not in host:port form", addr))?;
+    if host.is_empty() {
+        return Err(anyhow!("{:?} has no host", addr));
+    }
+    port.parse::<u16>()
+        .map_err(|_| anyhow!("{:?} has an invalid port", addr))?;
     Ok(())
 }
 
-fn resolve_config() -> Config {
-    Config {
-        listen_addr: "0.0.0.0:8081".to_string(),
-        upstream_addr: "0.0.0.0:8080".to_string(),
+// what the certificate of an upstream is checked against by default
+fn host_name(addr: &str) -> anyhow::Result<ServerName<'static>> {
+    let (host, _) = addr
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow!("{:?} is not in host:port form", addr))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    ServerName::try_from(host.to_string())
+        .map_err(|e| anyhow!("{:?} can not be checked against a certificate: {}", host, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(host: Option<&str>, path_prefix: &str, rewrite: Option<&str>) -> RouteConfig {
+        RouteConfig {
+            host: host.map(str::to_string),
+            path_prefix: path_prefix.to_string(),
+            rewrite: rewrite.map(str::to_string),
+            upstream: "api".to_string(),
+        }
+    }
+
+    #[test]
+    fn routes_match_whole_path_segments() {
+        let v1 = route(None, "/v1", None);
+        assert!(v1.matches(None, "/v1"));
+        assert!(v1.matches(None, "/v1/users"));
+        assert!(!v1.matches(None, "/v1beta"));
+        assert!(!v1.matches(None, "/v"));
+        let v1 = route(None, "/v1/", None);
+        assert!(v1.matches(None, "/v1/users"));
+        assert!(!v1.matches(None, "/v1"));
+        let root = route(None, "/", None);
+        assert!(root.matches(None, "/"));
+        assert!(root.matches(Some("example.com"), "/anything"));
+    }
+
+    #[test]
+    fn routes_match_hosts_case_insensitively() {
+        let api = route(Some("api.example.com"), "/", None);
+        assert!(api.matches(Some("API.example.com"), "/"));
+        assert!(!api.matches(Some("www.example.com"), "/"));
+        assert!(!api.matches(None, "/"));
+    }
+
+    #[test]
+    fn rewrites_join_with_exactly_one_slash() {
+        let cases = [
+            ("/v1", Some("/"), "/v1", "/"),
+            ("/v1", Some("/"), "/v1/users", "/users"),
+            ("/v1", Some("/api"), "/v1/users", "/api/users"),
+            ("/v1", Some("/api/"), "/v1/users", "/api/users"),
+            ("/v1", Some("/api"), "/v1", "/api"),
+            ("/v1/", Some("/api"), "/v1/users", "/api/users"),
+            ("/v1/", Some("/api/"), "/v1/users", "/api/users"),
+            ("/v1/", Some("/"), "/v1/", "/"),
+            ("/", Some("/api"), "/users", "/api/users"),
+            ("/v1", None, "/v1/users", "/v1/users"),
+        ];
+        for (prefix, rewrite, path, expected) in cases {
+            let uri: Uri = path.parse().unwrap();
+            assert_eq!(
+                route(None, prefix, rewrite).rewrite(&uri),
+                expected,
+                "{} -> {:?} on {}",
+                prefix,
+                rewrite,
+                path
+            );
+        }
+    }
+
+    fn pool(strategy: Strategy, addrs: &[&str]) -> Pool {
+        let config = UpstreamConfig {
+            addrs: addrs.iter().map(|addr| addr.to_string()).collect(),
+            strategy,
+            health_check: HealthCheckConfig::default(),
+            timeouts: TimeoutConfig::default(),
+            retry: RetryConfig::default(),
+            tls: None,
+        };
+        Pool::new(config, None).unwrap()
+    }
+
+    fn set_healthy(pool: &Pool, addr: &str, healthy: bool) {
+        let upstream = pool.upstreams.iter().find(|u| u.addr == addr).unwrap();
+        upstream.healthy.store(healthy, Ordering::Relaxed);
+    }
+
+    fn client(i: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, i])
+    }
+
+    // the upstreams picked by `n` connections that are closed right away
+    fn picks(pool: &Pool, n: usize, tried: &[String]) -> BTreeSet<String> {
+        (0..n)
+            .map(|i| pool.pick(client(i as u8), tried).unwrap().0.addr.clone())
+            .collect()
+    }
+
+    #[test]
+    fn strategies_skip_unhealthy_upstreams() {
+        let addrs = ["a:1", "b:1", "c:1"];
+        for strategy in [
+            Strategy::RoundRobin,
+            Strategy::LeastConnections,
+            Strategy::Random,
+            Strategy::ConsistentHash,
+        ] {
+            let pool = pool(strategy, &addrs);
+            set_healthy(&pool, "b:1", false);
+            let picked = picks(&pool, 50, &[]);
+            assert!(!picked.contains("b:1"), "{:?} picked b:1", strategy);
+            set_healthy(&pool, "a:1", false);
+            set_healthy(&pool, "c:1", false);
+            assert!(pool.pick(client(1), &[]).is_none(), "{:?}", strategy);
+        }
+    }
+
+    #[test]
+    fn strategies_prefer_untried_upstreams() {
+        let addrs = ["a:1", "b:1", "c:1"];
+        for strategy in [
+            Strategy::RoundRobin,
+            Strategy::LeastConnections,
+            Strategy::Random,
+            Strategy::ConsistentHash,
+        ] {
+            let pool = pool(strategy, &addrs);
+            let tried = ["a:1".to_string(), "c:1".to_string()];
+            let picked = picks(&pool, 50, &tried);
+            assert_eq!(
+                picked,
+                BTreeSet::from(["b:1".to_string()]),
+                "{:?}",
+                strategy
+            );
+            // once every upstream was tried, any healthy one is picked again
+            let tried: Vec<_> = addrs.iter().map(|addr| addr.to_string()).collect();
+            assert!(pool.pick(client(1), &tried).is_some(), "{:?}", strategy);
+            set_healthy(&pool, "b:1", false);
+            let picked = picks(&pool, 50, &tried[..2]);
+            assert!(!picked.contains("b:1"), "{:?} picked b:1", strategy);
+        }
+    }
+
+    #[test]
+    fn round_robin_rotates() {
+        let pool = pool(Strategy::RoundRobin, &["a:1", "b:1", "c:1"]);
+        let picked: Vec<_> = (0..6)
+            .map(|_| pool.pick(client(1), &[]).unwrap().0.addr.clone())
+            .collect();
+        assert_eq!(picked, ["a:1", "b:1", "c:1", "a:1", "b:1", "c:1"]);
+    }
+
+    #[test]
+    fn least_connections_counts_open_connections() {
+        let pool = pool(Strategy::LeastConnections, &["a:1", "b:1"]);
+        let first = pool.pick(client(1), &[]).unwrap();
+        let second = pool.pick(client(1), &[]).unwrap();
+        assert_ne!(first.0.addr, second.0.addr);
+        let busy = second.0.addr.clone();
+        // the upstream whose connection closed is the only free one
+        drop(first);
+        for _ in 0..5 {
+            let third = pool.pick(client(1), &[]).unwrap();
+            assert_ne!(third.0.addr, busy);
+        }
+        drop(second);
+        assert!(pool
+            .upstreams
+            .iter()
+            .all(|u| u.connections.load(Ordering::Relaxed) == 0));
+    }
+
+    #[test]
+    fn consistent_hash_sticks_to_an_upstream() {
+        let pool = pool(Strategy::ConsistentHash, &["a:1", "b:1", "c:1"]);
+        let owners: Vec<_> = (0..50)
+            .map(|i| pool.pick(client(i), &[]).unwrap().0.addr.clone())
+            .collect();
+        for _ in 0..3 {
+            for (i, owner) in owners.iter().enumerate() {
+                assert_eq!(&pool.pick(client(i as u8), &[]).unwrap().0.addr, owner);
+            }
+        }
+        assert_eq!(owners.iter().collect::<BTreeSet<_>>().len(), 3);
+
+        // only the clients of a dead upstream move, and they come back
+        set_healthy(&pool, "b:1", false);
+        for (i, owner) in owners.iter().enumerate() {
+            let addr = pool.pick(client(i as u8), &[]).unwrap().0.addr.clone();
+            if owner == "b:1" {
+                assert_ne!(addr, "b:1");
+            } else {
+                assert_eq!(&addr, owner);
+            }
+        }
+        set_healthy(&pool, "b:1", true);
+        for (i, owner) in owners.iter().enumerate() {
+            assert_eq!(&pool.pick(client(i as u8), &[]).unwrap().0.addr, owner);
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let retry = RetryConfig::default();
+        let backoffs: Vec<_> = (1..=7)
+            .map(|failures| retry.backoff(failures).as_millis())
+            .collect();
+        assert_eq!(backoffs, [50, 100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(retry.backoff(10_000), Duration::from_millis(1000));
+        let retry = RetryConfig {
+            attempts: 3,
+            backoff_ms: u64::MAX / 2,
+            max_backoff_ms: 5000,
+        };
+        assert_eq!(retry.backoff(1), Duration::from_millis(5000));
+        assert_eq!(retry.backoff(64), Duration::from_millis(5000));
+    }
+
+    fn validate(yaml: &str) -> anyhow::Result<()> {
+        serde_yaml::from_str::<Config>(yaml)?.validate()
+    }
+
+    #[test]
+    fn config_validation_points_at_the_mistake() {
+        let upstreams = "upstreams:\n  api:\n    addrs: [a:1, b:1]\n";
+        let cases = [
+            ("servers: []", "servers: at least one server is required"),
+            (
+                "servers:\n  - listen_addr: 127.0.0.1:80\n    upstream: web",
+                "servers[0]: upstream: unknown upstream group \"web\"",
+            ),
+            (
+                "servers:\n  - listen_addr: 127.0.0.1:80\n    upstream: api\n  - listen_addr: 127.0.0.1:80\n    upstream: api",
+                "servers[1].listen_addr: 127.0.0.1:80 is used more than once",
+            ),
+            (
+                "servers:\n  - listen_addr: 127.0.0.1:80",
+                "servers[0]: upstream: required in tcp mode",
+            ),
+            (
+                "servers:\n  - listen_addr: 127.0.0.1:80\n    mode: http",
+                "servers[0]: routes: at least one is required in http mode",
+            ),
+            (
+                "servers:\n  - listen_addr: 127.0.0.1:80\n    mode: http\n    routes:\n      - path_prefix: v1\n        upstream: api",
+                "servers[0]: routes[0].path_prefix: must start with /",
+            ),
+            (
+                "servers:\n  - listen_addr: 127.0.0.1:80\n    mode: http\n    routes:\n      - rewrite: v1\n        upstream: api",
+                "servers[0]: routes[0].rewrite: must start with /",
+            ),
+        ];
+        for (servers, expected) in cases {
+            let e = validate(&format!("{}{}", upstreams, servers)).unwrap_err();
+            assert_eq!(format!("{:#}", e), expected);
+        }
+
+        let servers = "servers:\n  - listen_addr: 127.0.0.1:80\n    upstream: api\n";
+        let cases = [
+            (
+                "{addrs: []}",
+                "upstreams.api: addrs: at least one is required",
+            ),
+            (
+                "{addrs: [a]}",
+                "upstreams.api: addrs[0]: \"a\" is not in host:port form",
+            ),
+            (
+                "{addrs: [a:1, a:1]}",
+                "upstreams.api: addrs[1]: a:1 is listed more than once",
+            ),
+            (
+                "{addrs: [a:x]}",
+                "upstreams.api: addrs[0]: \"a:x\" has an invalid port",
+            ),
+            (
+                "{addrs: [a:1], retry: {attempts: 0}}",
+                "upstreams.api: retry.attempts: at least one is required",
+            ),
+            (
+                "{addrs: [a:1], timeouts: {idle_secs: 0}}",
+                "upstreams.api: timeouts: must be positive",
+            ),
+            (
+                "{addrs: [a:1], health_check: {max_failures: 0}}",
+                "upstreams.api: health_check: interval_secs and max_failures must be positive",
+            ),
+        ];
+        for (upstream, expected) in cases {
+            let e = validate(&format!("upstreams:\n  api: {}\n{}", upstream, servers)).unwrap_err();
+            assert_eq!(format!("{:#}", e), expected);
+        }
+        assert!(validate(&format!("{}{}", upstreams, servers)).is_ok());
+    }
+
+    #[tokio::test]
+    async fn failed_binds_keep_the_running_config() -> anyhow::Result<()> {
+        let taken = TcpListener::bind("127.0.0.1:0").await?;
+        let yaml = format!(
+            "upstreams:\n  api:\n    addrs: [a:1]\nservers:\n  - listen_addr: {}\n    upstream: api\n",
+            taken.local_addr()?
+        );
+        let mut proxy = Proxy::new();
+        assert!(proxy.apply(serde_yaml::from_str(&yaml)?).await.is_err());
+        let snapshot = proxy.snapshot.borrow().clone();
+        assert!(snapshot.servers.is_empty() && snapshot.pools.is_empty());
+        assert!(proxy.listeners.is_empty() && proxy.checks.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn idle_http_clients_are_closed() -> anyhow::Result<()> {
+        use tokio::io::AsyncReadExt as _;
+
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let yaml = format!(
+            "upstreams:\n  api:\n    addrs: [127.0.0.1:1]\n    timeouts: {{idle_secs: 1}}\nservers:\n  - listen_addr: {}\n    mode: http\n    routes:\n      - upstream: api\n",
+            addr
+        );
+        let mut proxy = Proxy::new();
+        proxy.apply(serde_yaml::from_str(&yaml)?).await?;
+        let mut client = TcpStream::connect(addr).await?;
+        let start = Instant::now();
+        let mut buf = [0; 1];
+        let read = client.read(&mut buf);
+        assert_eq!(
+            tokio::time::timeout(Duration::from_secs(5), read).await??,
+            0
+        );
+        assert!(start.elapsed() >= Duration::from_millis(900));
+        Ok(())
+    }
+
+    // certificates are told apart by their bytes, the key never signs
+    #[derive(Debug)]
+    struct NoKey;
+
+    impl rustls::sign::SigningKey for NoKey {
+        fn choose_scheme(
+            &self,
+            _: &[rustls::SignatureScheme],
+        ) -> Option<Box<dyn rustls::sign::Signer>> {
+            None
+        }
+
+        fn algorithm(&self) -> rustls::SignatureAlgorithm {
+            rustls::SignatureAlgorithm::ECDSA
+        }
+    }
+
+    fn cert(id: u8) -> Arc<CertifiedKey> {
+        let chain = vec![CertificateDer::from(vec![id])];
+        Arc::new(CertifiedKey::new(chain, Arc::new(NoKey)))
+    }
+
+    #[test]
+    fn sni_picks_exact_then_wildcard_then_default() {
+        let names = [
+            ("example.com", 1),
+            ("api.example.com", 2),
+            ("*.example.com", 3),
+            ("*.internal.example.com", 4),
+        ];
+        let resolver = SniResolver {
+            names: names
+                .into_iter()
+                .map(|(name, id)| (name.to_string(), cert(id)))
+                .collect(),
+            default: cert(0),
+        };
+        let picked = |name| resolver.lookup(name).cert[0].as_ref()[0];
+        assert_eq!(picked(Some("example.com")), 1);
+        assert_eq!(picked(Some("API.Example.com")), 2);
+        assert_eq!(picked(Some("www.example.com")), 3);
+        assert_eq!(picked(Some("db.internal.example.com")), 4);
+        // a wildcard covers a single label only
+        assert_eq!(picked(Some("a.b.example.com")), 0);
+        assert_eq!(picked(Some("example.org")), 0);
+        assert_eq!(picked(None), 0);
+    }
+
+    #[test]
+    fn rewrites_keep_the_query() {
+        let uri: Uri = "/v1/users?page=2".parse().unwrap();
+        assert_eq!(route(None, "/v1", Some("/")).rewrite(&uri), "/users?page=2");
     }
 }
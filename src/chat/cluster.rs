@@ -0,0 +1,223 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use dashmap::DashMap;
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::{lookup_host, TcpListener, TcpStream},
+    sync::mpsc,
+    time::Duration,
+};
+use tokio_util::{
+    codec::{Framed, FramedRead, LinesCodec},
+    sync::CancellationToken,
+};
+use tracing::{info, warn};
+
+use super::{
+    config::ClusterConfig,
+    message::{Event, Message},
+    server,
+    state::State,
+};
+
+// ids of this many recently relayed messages are remembered to break loops
+const SEEN_CAPACITY: usize = 4096;
+// messages for a node beyond this many are dropped while its link is slow
+const LINK_QUEUE: usize = 1024;
+const MAX_LINK_LINE: usize = 1024 * 1024;
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// A room event relayed between nodes.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Envelope {
+    // unique in the cluster: the id of the origin node, when it started and
+    // a sequence number
+    pub(crate) id: String,
+    pub(crate) room: String,
+    pub(crate) event: Arc<Event>,
+}
+
+/// The links of this node to the other nodes of the cluster.
+#[derive(Debug, Default)]
+pub(crate) struct Cluster {
+    // node id and start time, a restarted node counts from 0 again
+    id_prefix: String,
+    next_seq: AtomicU64,
+    // cluster address of a linked node -> the queue of its link
+    links: DashMap<String, mpsc::Sender<Arc<Envelope>>>,
+    seen: Mutex<Seen>,
+}
+
+#[derive(Debug, Default)]
+struct Seen {
+    ids: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl Cluster {
+    pub(crate) fn new(config: &ClusterConfig) -> Self {
+        let node_id = config
+            .node_id
+            .clone()
+            .unwrap_or_else(|| std::process::id().to_string());
+        let started = chrono::Utc::now().timestamp_micros();
+        Self {
+            id_prefix: format!("{}-{:x}", node_id, started),
+            ..Default::default()
+        }
+    }
+
+    /// Send an event that originated on this node to the linked nodes.
+    pub(crate) fn publish(&self, room: &str, event: &Arc<Event>) {
+        // the content of attachments stays on the node it was uploaded to
+        if self.links.is_empty() || matches!(event.message, Message::Attachment { .. }) {
+            return;
+        }
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        let envelope = Envelope {
+            id: format!("{}:{}", self.id_prefix, seq),
+            room: room.to_string(),
+            event: event.clone(),
+        };
+        self.first_seen(&envelope.id);
+        self.forward(&Arc::new(envelope));
+    }
+
+    /// Whether a relayed message is new, every message is handled only once
+    /// no matter how many paths it took.
+    pub(crate) fn first_seen(&self, id: &str) -> bool {
+        let mut seen = self.seen.lock().unwrap();
+        if !seen.ids.insert(id.to_string()) {
+            return false;
+        }
+        seen.order.push_back(id.to_string());
+        if seen.order.len() > SEEN_CAPACITY {
+            if let Some(oldest) = seen.order.pop_front() {
+                seen.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    pub(crate) fn forward(&self, envelope: &Arc<Envelope>) {
+        for link in self.links.iter() {
+            if let Err(e) = link.value().try_send(envelope.clone()) {
+                warn!(
+                    "Failed to relay {} to node {}: {}",
+                    envelope.id,
+                    link.key(),
+                    e
+                );
+            }
+        }
+    }
+
+    /// Cluster addresses of the nodes this one is linked to.
+    pub(crate) fn nodes(&self) -> Vec<String> {
+        let mut nodes: Vec<_> = self.links.iter().map(|link| link.key().clone()).collect();
+        nodes.sort();
+        nodes
+    }
+}
+
+/// Keep a link to another node up, reconnecting with a backoff, and send it
+/// the messages relayed by this node.
+pub(crate) async fn link(state: Arc<State>, addr: String, shutdown: CancellationToken) {
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        let stream = tokio::select! {
+            stream = TcpStream::connect(&addr) => stream,
+            _ = shutdown.cancelled() => return,
+        };
+        match stream {
+            Ok(stream) => {
+                info!("Linked to node {}", addr);
+                backoff = MIN_BACKOFF;
+                let (tx, mut rx) = mpsc::channel(LINK_QUEUE);
+                state.cluster.links.insert(addr.clone(), tx.clone());
+                let (mut sink, mut stream) = Framed::new(stream, LinesCodec::new()).split();
+                let reason = loop {
+                    let envelope = tokio::select! {
+                        Some(envelope) = rx.recv() => envelope,
+                        // the other node never writes, this only ends the link
+                        _ = stream.next() => break "closed by the node".to_string(),
+                        _ = shutdown.cancelled() => break "shutting down".to_string(),
+                    };
+                    let line = serde_json::to_string(&*envelope).expect("events serialize");
+                    if let Err(e) = sink.send(line).await {
+                        break e.to_string();
+                    }
+                };
+                state
+                    .cluster
+                    .links
+                    .remove_if(&addr, |_, link| link.same_channel(&tx));
+                if shutdown.is_cancelled() {
+                    return;
+                }
+                warn!("Lost link to node {}: {}", addr, reason);
+            }
+            Err(e) => warn!("Failed to link to node {}: {}", addr, e),
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => {}
+            _ = shutdown.cancelled() => return,
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Accept links from the configured nodes and relay what they send.
+pub(crate) async fn accept_links(
+    state: Arc<State>,
+    listener: TcpListener,
+    shutdown: CancellationToken,
+) {
+    let mut allowed = HashSet::<IpAddr>::new();
+    for peer in &state.config.cluster.peers {
+        match lookup_host(peer).await {
+            Ok(addrs) => allowed.extend(addrs.map(|addr| addr.ip())),
+            Err(e) => warn!("Failed to resolve node {}: {}", peer, e),
+        }
+    }
+    while let Some((stream, addr)) = server::accept(&listener, "cluster link", &shutdown).await {
+        if !allowed.contains(&addr.ip()) {
+            warn!("Rejected cluster link from unknown node {}", addr);
+            continue;
+        }
+        info!("Accept cluster link from {}", addr);
+        let state = state.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            let mut lines = FramedRead::new(stream, LinesCodec::new_with_max_length(MAX_LINK_LINE));
+            loop {
+                let line = tokio::select! {
+                    line = lines.next() => line,
+                    _ = shutdown.cancelled() => break,
+                };
+                let envelope = match line {
+                    Some(Ok(line)) => serde_json::from_str::<Envelope>(&line),
+                    Some(Err(e)) => {
+                        warn!("Failed to read from node {}: {}", addr, e);
+                        break;
+                    }
+                    None => break,
+                };
+                match envelope {
+                    Ok(envelope) => state.relay(envelope).await,
+                    Err(e) => warn!("Invalid message from node {}: {}", addr, e),
+                }
+            }
+            info!("Cluster link from {} closed", addr);
+        });
+    }
+}
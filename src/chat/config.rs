@@ -0,0 +1,254 @@
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub listen_addr: String,
+    // the websocket gateway is served on ws://<websocket_addr>/ws, off by
+    // default
+    pub websocket_addr: Option<String>,
+    // Prometheus metrics are served on http://<metrics_addr>/metrics
+    pub metrics_addr: Option<String>,
+    pub tls: Option<TlsConfig>,
+    pub attachments: AttachmentConfig,
+    pub username: UsernameConfig,
+    pub history: HistoryConfig,
+    pub log: LogConfig,
+    pub slow_consumer: SlowConsumerPolicy,
+    pub rate_limit: RateLimitConfig,
+    pub heartbeat: HeartbeatConfig,
+    pub moderation: ModerationConfig,
+    pub cluster: ClusterConfig,
+    // how long to wait for the outgoing queues to flush on shutdown
+    pub shutdown_timeout_secs: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TlsConfig {
+    pub listen_addr: String,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    // enables mutual TLS, the CN of a verified client certificate is used as
+    // the username instead of prompting for one
+    pub client_ca: Option<PathBuf>,
+    // reject clients without a certificate instead of prompting them
+    #[serde(default)]
+    pub require_client_cert: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct AttachmentConfig {
+    // peers speaking the length prefixed binary protocol connect here, the
+    // line based listeners can't carry attachments
+    pub listen_addr: Option<String>,
+    pub max_size: usize,
+    pub chunk_size: usize,
+    // uploads have their own budget, separate from chat lines, a peer over it
+    // is read from more slowly instead of losing chunks
+    pub bytes_per_sec: f64,
+    pub byte_burst: f64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct UsernameConfig {
+    pub min_len: usize,
+    pub max_len: usize,
+    // every character a username may contain
+    pub charset: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct HistoryConfig {
+    // how many chat messages are kept per room
+    pub capacity: usize,
+    // how many of them are replayed to a newcomer
+    pub replay: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub messages_per_sec: f64,
+    pub message_burst: f64,
+    pub bytes_per_sec: f64,
+    pub byte_burst: f64,
+    pub max_line_length: usize,
+    // peers are warned on every violation, muted from the `mute_after`th one
+    // and disconnected at the `disconnect_after`th one
+    pub mute_after: u32,
+    pub mute_secs: u64,
+    pub disconnect_after: u32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct HeartbeatConfig {
+    // idle JSON peers are pinged this often, writing to a dead connection
+    // fails and gets the peer removed, idle text peers get TCP keepalive
+    // probes after the same time
+    pub interval_secs: u64,
+    // disconnect JSON peers that sent nothing, not even a pong, for this
+    // long, text peers have no way to answer a ping
+    pub timeout_secs: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ModerationConfig {
+    // usernames that are admins when they log in with a client certificate,
+    // a typed username proves nothing
+    pub admins: Vec<String>,
+    // anyone sending `/admin <secret>` becomes an admin
+    pub secret: Option<String>,
+    // bans only last until a restart without one
+    pub ban_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ClusterConfig {
+    // must be unique in the cluster as it prefixes the ids of relayed
+    // messages, the process id when missing
+    pub node_id: Option<String>,
+    // the other nodes link to this address
+    pub listen_addr: Option<String>,
+    // cluster addresses of the other nodes, every node links to each of them
+    // and only accepts links from their hosts
+    pub peers: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    // off unless asked for, nothing is written to disk by default
+    pub enabled: bool,
+    pub dir: PathBuf,
+    // the active file is rotated once it would grow past this size
+    pub max_file_bytes: u64,
+    // rotated files beyond this count are deleted, oldest first
+    pub max_files: usize,
+}
+
+/// What to do when a peer's outgoing queue is full.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowConsumerPolicy {
+    #[default]
+    DropOldest,
+    DropNewest,
+    Disconnect,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen_addr: "0.0.0.0:8080".to_string(),
+            websocket_addr: None,
+            metrics_addr: None,
+            tls: None,
+            attachments: AttachmentConfig::default(),
+            username: UsernameConfig::default(),
+            history: HistoryConfig::default(),
+            log: LogConfig::default(),
+            slow_consumer: SlowConsumerPolicy::default(),
+            rate_limit: RateLimitConfig::default(),
+            heartbeat: HeartbeatConfig::default(),
+            moderation: ModerationConfig::default(),
+            cluster: ClusterConfig::default(),
+            shutdown_timeout_secs: 5,
+        }
+    }
+}
+
+impl Default for AttachmentConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: None,
+            max_size: 1024 * 1024,
+            chunk_size: 16 * 1024,
+            bytes_per_sec: 256.0 * 1024.0,
+            byte_burst: 1024.0 * 1024.0,
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            messages_per_sec: 5.0,
+            message_burst: 10.0,
+            bytes_per_sec: 4096.0,
+            byte_burst: 8192.0,
+            max_line_length: 4096,
+            mute_after: 3,
+            mute_secs: 30,
+            disconnect_after: 6,
+        }
+    }
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: 30,
+            timeout_secs: None,
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dir: PathBuf::from("tmp/chat"),
+            max_file_bytes: 8 * 1024 * 1024,
+            max_files: 10,
+        }
+    }
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 100,
+            replay: 10,
+        }
+    }
+}
+
+impl Default for UsernameConfig {
+    fn default() -> Self {
+        Self {
+            min_len: 1,
+            max_len: 16,
+            charset: ('a'..='z')
+                .chain('A'..='Z')
+                .chain('0'..='9')
+                .chain(['_', '-', '.'])
+                .collect(),
+        }
+    }
+}
+
+impl UsernameConfig {
+    pub(crate) fn validate(&self, username: &str) -> anyhow::Result<()> {
+        let len = username.chars().count();
+        if len < self.min_len || len > self.max_len {
+            return Err(anyhow!(
+                "Username must be {} to {} characters",
+                self.min_len,
+                self.max_len
+            ));
+        }
+        if let Some(c) = username.chars().find(|c| !self.charset.contains(*c)) {
+            return Err(anyhow!("Username must not contain {:?}", c));
+        }
+        Ok(())
+    }
+}
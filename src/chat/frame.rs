@@ -0,0 +1,343 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::anyhow;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder, LengthDelimitedCodec};
+
+use super::config::AttachmentConfig;
+
+// at most this many uploads of a peer can be in flight
+const MAX_UPLOADS: usize = 4;
+
+/// A frame of the binary protocol. On the wire every frame is length
+/// prefixed and starts with a one byte tag, integers are big endian.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    /// 0: a line of the line based protocol, in UTF-8
+    Text(String),
+    /// 1: id u32, size u64, blake3 checksum [u8; 32], file name in UTF-8
+    AttachmentStart {
+        id: u32,
+        size: u64,
+        checksum: [u8; 32],
+        name: String,
+    },
+    /// 2: id u32, payload
+    AttachmentChunk { id: u32, data: Bytes },
+    /// 3: id u32
+    AttachmentEnd { id: u32 },
+    /// 4: id u32, then an error message in UTF-8 if the transfer failed
+    Ack { id: u32, error: Option<String> },
+}
+
+/// [`Frame`]s over a [`LengthDelimitedCodec`].
+#[derive(Debug)]
+pub struct FrameCodec {
+    inner: LengthDelimitedCodec,
+}
+
+/// A verified attachment.
+#[derive(Debug)]
+pub(crate) struct Attachment {
+    pub(crate) name: String,
+    pub(crate) data: Bytes,
+    pub(crate) checksum: blake3::Hash,
+}
+
+/// Attachments a peer is uploading, keyed by the id the peer picked.
+#[derive(Debug, Default)]
+pub(crate) struct Uploads {
+    pending: HashMap<u32, Upload>,
+    // failed uploads whose remaining frames are ignored until their end
+    rejected: HashSet<u32>,
+}
+
+#[derive(Debug)]
+struct Upload {
+    name: String,
+    size: u64,
+    checksum: [u8; 32],
+    data: BytesMut,
+}
+
+impl Frame {
+    const TEXT: u8 = 0;
+    const ATTACHMENT_START: u8 = 1;
+    const ATTACHMENT_CHUNK: u8 = 2;
+    const ATTACHMENT_END: u8 = 3;
+    const ACK: u8 = 4;
+
+    /// The attachment the frame belongs to.
+    pub fn attachment_id(&self) -> Option<u32> {
+        match self {
+            Self::Text(_) => None,
+            Self::AttachmentStart { id, .. }
+            | Self::AttachmentChunk { id, .. }
+            | Self::AttachmentEnd { id }
+            | Self::Ack { id, .. } => Some(*id),
+        }
+    }
+
+    /// Size of the payload, what the rate limiters charge for.
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Self::Text(line) => line.len(),
+            Self::AttachmentStart { name, .. } => name.len(),
+            Self::AttachmentChunk { data, .. } => data.len(),
+            Self::AttachmentEnd { .. } | Self::Ack { .. } => 0,
+        }
+    }
+
+    /// Split an attachment into the frames that transfer it.
+    pub(crate) fn attachment(
+        id: u32,
+        name: &str,
+        data: &Bytes,
+        checksum: &blake3::Hash,
+        chunk_size: usize,
+    ) -> Vec<Frame> {
+        let mut frames = vec![Self::AttachmentStart {
+            id,
+            size: data.len() as u64,
+            checksum: *checksum.as_bytes(),
+            name: name.to_string(),
+        }];
+        let mut offset = 0;
+        while offset < data.len() {
+            let end = (offset + chunk_size).min(data.len());
+            frames.push(Self::AttachmentChunk {
+                id,
+                data: data.slice(offset..end),
+            });
+            offset = end;
+        }
+        frames.push(Self::AttachmentEnd { id });
+        frames
+    }
+
+    fn encode(self, dst: &mut BytesMut) {
+        match self {
+            Self::Text(line) => {
+                dst.put_u8(Self::TEXT);
+                dst.put_slice(line.as_bytes());
+            }
+            Self::AttachmentStart {
+                id,
+                size,
+                checksum,
+                name,
+            } => {
+                dst.put_u8(Self::ATTACHMENT_START);
+                dst.put_u32(id);
+                dst.put_u64(size);
+                dst.put_slice(&checksum);
+                dst.put_slice(name.as_bytes());
+            }
+            Self::AttachmentChunk { id, data } => {
+                dst.put_u8(Self::ATTACHMENT_CHUNK);
+                dst.put_u32(id);
+                dst.put_slice(&data);
+            }
+            Self::AttachmentEnd { id } => {
+                dst.put_u8(Self::ATTACHMENT_END);
+                dst.put_u32(id);
+            }
+            Self::Ack { id, error } => {
+                dst.put_u8(Self::ACK);
+                dst.put_u32(id);
+                if let Some(error) = error {
+                    dst.put_slice(error.as_bytes());
+                }
+            }
+        }
+    }
+
+    fn decode(mut src: Bytes) -> anyhow::Result<Self> {
+        if src.is_empty() {
+            return Err(anyhow!("Empty frame"));
+        }
+        let tag = src.get_u8();
+        if tag != Self::TEXT && src.len() < 4 {
+            return Err(anyhow!("Frame {} is too short", tag));
+        }
+        let frame = match tag {
+            Self::TEXT => Self::Text(String::from_utf8(src.to_vec())?),
+            Self::ATTACHMENT_START => {
+                if src.len() < 4 + 8 + 32 {
+                    return Err(anyhow!("Frame {} is too short", tag));
+                }
+                let id = src.get_u32();
+                let size = src.get_u64();
+                let mut checksum = [0; 32];
+                src.copy_to_slice(&mut checksum);
+                Self::AttachmentStart {
+                    id,
+                    size,
+                    checksum,
+                    name: String::from_utf8(src.to_vec())?,
+                }
+            }
+            Self::ATTACHMENT_CHUNK => Self::AttachmentChunk {
+                id: src.get_u32(),
+                data: src,
+            },
+            Self::ATTACHMENT_END => Self::AttachmentEnd { id: src.get_u32() },
+            Self::ACK => {
+                let id = src.get_u32();
+                let error = (!src.is_empty())
+                    .then(|| String::from_utf8(src.to_vec()))
+                    .transpose()?;
+                Self::Ack { id, error }
+            }
+            tag => return Err(anyhow!("Unknown frame {}", tag)),
+        };
+        Ok(frame)
+    }
+}
+
+impl FrameCodec {
+    /// Frames longer than `max_frame_length` are rejected.
+    pub fn new(max_frame_length: usize) -> Self {
+        Self {
+            inner: LengthDelimitedCodec::builder()
+                .max_frame_length(max_frame_length)
+                .new_codec(),
+        }
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Frame;
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.inner.decode(src)? {
+            Some(frame) => Frame::decode(frame.freeze()).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Encoder<Frame> for FrameCodec {
+    type Error = anyhow::Error;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let mut buf = BytesMut::new();
+        frame.encode(&mut buf);
+        self.inner.encode(buf.freeze(), dst)?;
+        Ok(())
+    }
+}
+
+impl Uploads {
+    /// Feed an attachment frame, returning the attachment once it is complete
+    /// and its checksum verified. A failed upload is only reported once.
+    pub(crate) fn receive(
+        &mut self,
+        frame: Frame,
+        config: &AttachmentConfig,
+    ) -> anyhow::Result<Option<Attachment>> {
+        let id = frame.attachment_id().unwrap_or_default();
+        if self.rejected.contains(&id) {
+            match frame {
+                Frame::AttachmentStart { .. } => {
+                    self.rejected.remove(&id);
+                }
+                Frame::AttachmentEnd { .. } => {
+                    self.rejected.remove(&id);
+                    return Ok(None);
+                }
+                _ => return Ok(None),
+            }
+        }
+        let end = matches!(frame, Frame::AttachmentEnd { .. });
+        let result = self.try_receive(frame, config);
+        if result.is_err() {
+            self.pending.remove(&id);
+            if !end {
+                self.rejected.insert(id);
+            }
+        }
+        result
+    }
+
+    /// Turn an upload down before it started, its remaining frames are
+    /// ignored.
+    pub(crate) fn reject(&mut self, id: u32) {
+        if !self.pending.contains_key(&id) {
+            self.rejected.insert(id);
+        }
+    }
+
+    fn try_receive(
+        &mut self,
+        frame: Frame,
+        config: &AttachmentConfig,
+    ) -> anyhow::Result<Option<Attachment>> {
+        match frame {
+            Frame::AttachmentStart {
+                id,
+                size,
+                checksum,
+                name,
+            } => {
+                if size > config.max_size as u64 {
+                    return Err(anyhow!("Attachment exceeds {} bytes", config.max_size));
+                }
+                if name.is_empty() || name.contains(['/', '\\']) {
+                    return Err(anyhow!("Invalid file name: {:?}", name));
+                }
+                if self.pending.contains_key(&id) {
+                    return Err(anyhow!("Attachment {} is already in progress", id));
+                }
+                if self.pending.len() >= MAX_UPLOADS {
+                    return Err(anyhow!("At most {} uploads at a time", MAX_UPLOADS));
+                }
+                let upload = Upload {
+                    name,
+                    size,
+                    checksum,
+                    data: BytesMut::with_capacity(size as usize),
+                };
+                self.pending.insert(id, upload);
+                Ok(None)
+            }
+            Frame::AttachmentChunk { id, data } => {
+                let upload = self.get(id)?;
+                if (upload.data.len() + data.len()) as u64 > upload.size {
+                    return Err(anyhow!("Attachment {} exceeds its size", id));
+                }
+                upload.data.extend_from_slice(&data);
+                Ok(None)
+            }
+            Frame::AttachmentEnd { id } => {
+                let upload = self.get(id)?;
+                if upload.data.len() as u64 != upload.size {
+                    return Err(anyhow!(
+                        "Attachment {} is {} bytes, expected {}",
+                        id,
+                        upload.data.len(),
+                        upload.size
+                    ));
+                }
+                let upload = self.pending.remove(&id).expect("upload exists");
+                let checksum = blake3::hash(&upload.data);
+                if checksum != blake3::Hash::from(upload.checksum) {
+                    return Err(anyhow!("Checksum mismatch for attachment {}", id));
+                }
+                Ok(Some(Attachment {
+                    name: upload.name,
+                    data: upload.data.freeze(),
+                    checksum,
+                }))
+            }
+            frame => Err(anyhow!("Unexpected frame: {:?}", frame)),
+        }
+    }
+
+    fn get(&mut self, id: u32) -> anyhow::Result<&mut Upload> {
+        self.pending
+            .get_mut(&id)
+            .ok_or_else(|| anyhow!("Unknown attachment {}", id))
+    }
+}
@@ -0,0 +1,127 @@
+use tokio::time::{Duration, Instant};
+
+use super::{
+    config::{AttachmentConfig, RateLimitConfig},
+    STRIKE_RESET,
+};
+
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Per-peer flood protection, escalating from warnings to a mute to a
+/// disconnect while the peer keeps exceeding its limits.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    messages: TokenBucket,
+    bytes: TokenBucket,
+    uploads: TokenBucket,
+    strikes: u32,
+    last_strike: Option<Instant>,
+    muted_until: Option<Instant>,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Verdict {
+    Allow,
+    Warn,
+    Mute(Duration),
+    Muted,
+    Disconnect,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, capacity: f64) -> Self {
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            updated: Instant::now(),
+        }
+    }
+
+    fn try_take(&mut self, n: f64) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+        // a single message larger than the bucket would never get through
+        let n = n.min(self.capacity);
+        if self.tokens < n {
+            return false;
+        }
+        self.tokens -= n;
+        true
+    }
+
+    // take `n` even if it means going into debt, returns how long it takes
+    // to pay it back
+    fn borrow(&mut self, n: f64) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity) - n;
+        self.updated = now;
+        if self.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::try_from_secs_f64(-self.tokens / self.rate).unwrap_or(Duration::MAX)
+    }
+}
+
+impl RateLimiter {
+    pub(crate) fn new(config: &RateLimitConfig, attachments: &AttachmentConfig) -> Self {
+        Self {
+            messages: TokenBucket::new(config.messages_per_sec, config.message_burst),
+            bytes: TokenBucket::new(config.bytes_per_sec, config.byte_burst),
+            uploads: TokenBucket::new(attachments.bytes_per_sec, attachments.byte_burst),
+            strikes: 0,
+            last_strike: None,
+            muted_until: None,
+        }
+    }
+
+    /// How long to wait before reading on after an upload chunk of `len`
+    /// bytes.
+    pub(crate) fn upload_delay(&mut self, len: usize) -> Duration {
+        self.uploads.borrow(len as f64)
+    }
+
+    pub(crate) fn check(&mut self, len: usize, config: &RateLimitConfig) -> Verdict {
+        let now = Instant::now();
+        // take from both buckets, a message must not drain one for free
+        let within_messages = self.messages.try_take(1.0);
+        let within_bytes = self.bytes.try_take(len as f64);
+        let muted = self.muted_until.is_some_and(|until| until > now);
+        if within_messages && within_bytes {
+            return if muted {
+                Verdict::Muted
+            } else {
+                Verdict::Allow
+            };
+        }
+
+        if self
+            .last_strike
+            .is_some_and(|last| now.duration_since(last) > STRIKE_RESET)
+        {
+            self.strikes = 0;
+        }
+        self.strikes += 1;
+        self.last_strike = Some(now);
+        if self.strikes >= config.disconnect_after {
+            Verdict::Disconnect
+        } else if muted {
+            Verdict::Muted
+        } else if self.strikes >= config.mute_after {
+            let duration = Duration::from_secs(config.mute_secs);
+            self.muted_until = Some(now + duration);
+            Verdict::Mute(duration)
+        } else {
+            Verdict::Warn
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use anyhow::Context;
+use chrono::Utc;
+use tokio::sync::{mpsc, oneshot};
+use tracing::warn;
+
+use super::{config::LogConfig, message::Event};
+
+/// Append-only on-disk log of the room events, one JSON record per line. The
+/// files are written on a thread of their own, off the async runtime.
+#[derive(Debug)]
+pub(crate) struct ChatLog {
+    dir: PathBuf,
+    // taken on close, which ends the writer once the queue is drained
+    queue: Mutex<Option<mpsc::UnboundedSender<Arc<Event>>>>,
+    // resolves when the writer is done
+    done: Mutex<Option<oneshot::Receiver<()>>>,
+}
+
+/// Owns the files of a [`ChatLog`].
+struct Writer {
+    dir: PathBuf,
+    max_file_bytes: u64,
+    max_files: usize,
+    // the active file and its size
+    file: File,
+    size: u64,
+}
+
+impl ChatLog {
+    pub(crate) const ACTIVE: &'static str = "chat.log";
+
+    pub(crate) fn open(config: &LogConfig) -> anyhow::Result<Self> {
+        fs::create_dir_all(&config.dir)
+            .with_context(|| format!("Can not create log dir: {}", config.dir.display()))?;
+        let file = Self::open_active(&config.dir)?;
+        let size = file.metadata()?.len();
+        let mut writer = Writer {
+            dir: config.dir.clone(),
+            max_file_bytes: config.max_file_bytes,
+            max_files: config.max_files,
+            file,
+            size,
+        };
+        let (queue, mut events) = mpsc::unbounded_channel::<Arc<Event>>();
+        let (done, closed) = oneshot::channel();
+        thread::Builder::new()
+            .name("chat-log".to_string())
+            .spawn(move || {
+                while let Some(event) = events.blocking_recv() {
+                    if let Err(e) = writer.append(&event) {
+                        warn!("Failed to append to the chat log: {}", e);
+                    }
+                }
+                let _ = done.send(());
+            })
+            .context("Can not start the chat log writer")?;
+        Ok(Self {
+            dir: config.dir.clone(),
+            queue: Mutex::new(Some(queue)),
+            done: Mutex::new(Some(closed)),
+        })
+    }
+
+    fn open_active(dir: &Path) -> anyhow::Result<File> {
+        let path = dir.join(Self::ACTIVE);
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Can not open log file: {}", path.display()))
+    }
+
+    /// Queue an event for the writer, events after [`ChatLog::close`] are
+    /// dropped.
+    pub(crate) fn append(&self, event: Arc<Event>) {
+        if let Some(queue) = &*self.queue.lock().unwrap() {
+            let _ = queue.send(event);
+        }
+    }
+
+    /// Wait for the queued events to be written.
+    pub(crate) async fn close(&self) {
+        drop(self.queue.lock().unwrap().take());
+        let done = self.done.lock().unwrap().take();
+        if let Some(done) = done {
+            let _ = done.await;
+        }
+    }
+
+    /// The rotated files oldest first, followed by the active file.
+    fn segments(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+        let mut rotated = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            if name.starts_with("chat.") && name.ends_with(".log") && name != Self::ACTIVE {
+                rotated.push(dir.join(name));
+            }
+        }
+        rotated.sort();
+        rotated.push(dir.join(Self::ACTIVE));
+        Ok(rotated)
+    }
+
+    pub(crate) fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl Writer {
+    fn append(&mut self, event: &Event) -> anyhow::Result<()> {
+        let mut line = serde_json::to_string(event)?;
+        line.push('\n');
+        if self.size > 0 && self.size + line.len() as u64 > self.max_file_bytes {
+            self.file = self.rotate()?;
+            self.size = 0;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Move the active file aside and start a new one.
+    fn rotate(&self) -> anyhow::Result<File> {
+        let rotated = format!("chat.{}.log", Utc::now().format("%Y%m%d%H%M%S%3f"));
+        fs::rename(self.dir.join(ChatLog::ACTIVE), self.dir.join(rotated))?;
+        let segments = ChatLog::segments(&self.dir)?;
+        // the last segment is the active file, which is gone at this point
+        let rotated = &segments[..segments.len() - 1];
+        for path in &rotated[..rotated.len().saturating_sub(self.max_files)] {
+            fs::remove_file(path)?;
+        }
+        ChatLog::open_active(&self.dir)
+    }
+}
+
+/// Every event in the chat log under `dir`, oldest first.
+pub fn read_log(dir: &Path) -> anyhow::Result<Vec<Event>> {
+    let mut events = Vec::new();
+    for path in ChatLog::segments(dir)? {
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            // a torn last line is left behind by a crash mid-write
+            match serde_json::from_str(&line) {
+                Ok(event) => events.push(event),
+                Err(e) => warn!("Skipping bad record in {}: {}", path.display(), e),
+            }
+        }
+    }
+    Ok(events)
+}
@@ -0,0 +1,397 @@
+use std::{fmt, str::FromStr, sync::Arc};
+
+use anyhow::anyhow;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use tokio::time::Duration;
+
+use super::MAX_ROOM_NAME_LEN;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Proto {
+    #[default]
+    Text,
+    Json,
+}
+
+// the first line a client sends to switch protocols, e.g. {"proto":"json"}
+#[derive(Debug, Deserialize)]
+pub(crate) struct Hello {
+    pub(crate) proto: Proto,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct Login {
+    pub(crate) username: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Event {
+    pub timestamp: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender_id: Option<u64>,
+    #[serde(flatten)]
+    pub message: Message,
+}
+
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    UserJoined {
+        room: String,
+        username: String,
+    },
+    UserLeft {
+        room: String,
+        username: String,
+    },
+    Chat {
+        room: String,
+        sender: String,
+        content: String,
+    },
+    Typing {
+        room: String,
+        username: String,
+    },
+    Attachment {
+        room: String,
+        sender: String,
+        name: String,
+        size: usize,
+        #[serde_as(as = "DisplayFromStr")]
+        checksum: blake3::Hash,
+        // only peers of the binary protocol receive the content
+        #[serde(skip)]
+        data: Bytes,
+    },
+    // tells a peer of the binary protocol how its upload went
+    Ack {
+        id: u32,
+        error: Option<String>,
+    },
+    Away {
+        room: String,
+        username: String,
+        reason: Option<String>,
+    },
+    Back {
+        room: String,
+        username: String,
+    },
+    Private {
+        sender: String,
+        content: String,
+    },
+    History {
+        event: Arc<Event>,
+    },
+    Prompt {
+        content: String,
+    },
+    Notice {
+        content: String,
+    },
+    Error {
+        content: String,
+    },
+    Shutdown {
+        content: String,
+    },
+    Ping,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum Command {
+    Join { room: String },
+    Leave,
+    Rooms,
+    Msg { to: String, content: String },
+    History { n: Option<usize> },
+    Who,
+    Away { reason: Option<String> },
+    Back,
+    // only sent by JSON clients, text clients never see typing notifications
+    Typing,
+    Pong,
+    Admin { secret: String },
+    Kick { user: String },
+    Mute { user: String, secs: u64 },
+    // a user or an ip address
+    Ban { target: String },
+    Unban { target: String },
+    Chat { content: String },
+}
+
+impl Proto {
+    pub(crate) fn wants(&self, event: &Event) -> bool {
+        !(*self == Self::Text && matches!(event.message, Message::Typing { .. }))
+    }
+
+    pub(crate) fn encode(&self, event: &Event) -> String {
+        match self {
+            Self::Text => event.to_string(),
+            Self::Json => serde_json::to_string(event).expect("event is always serializable"),
+        }
+    }
+
+    pub(crate) fn decode(&self, line: &str) -> anyhow::Result<Command> {
+        match self {
+            Self::Text => line.parse(),
+            Self::Json => {
+                let command = serde_json::from_str(line)?;
+                if let Command::Join { room } = &command {
+                    validate_room(room)?;
+                }
+                Ok(command)
+            }
+        }
+    }
+
+    pub(crate) fn decode_login(&self, line: &str) -> anyhow::Result<String> {
+        let username = match self {
+            Self::Text => line.to_string(),
+            Self::Json => serde_json::from_str::<Login>(line)?.username,
+        };
+        Ok(username.trim().to_string())
+    }
+}
+
+impl Event {
+    pub fn new(sender_id: Option<u64>, message: Message) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            sender_id,
+            message,
+        }
+    }
+}
+
+impl From<Message> for Event {
+    fn from(message: Message) -> Self {
+        Self::new(None, message)
+    }
+}
+
+impl Message {
+    /// The room of a room event.
+    pub fn room(&self) -> Option<&str> {
+        match self {
+            Self::UserJoined { room, .. }
+            | Self::UserLeft { room, .. }
+            | Self::Chat { room, .. }
+            | Self::Attachment { room, .. } => Some(room),
+            _ => None,
+        }
+    }
+
+    pub fn user_joined(room: &str, username: &str) -> Self {
+        Self::UserJoined {
+            room: room.to_string(),
+            username: username.to_string(),
+        }
+    }
+    pub fn user_left(room: &str, username: &str) -> Self {
+        Self::UserLeft {
+            room: room.to_string(),
+            username: username.to_string(),
+        }
+    }
+    pub fn chat(room: &str, sender: impl Into<String>, content: impl Into<String>) -> Self {
+        Self::Chat {
+            room: room.to_string(),
+            sender: sender.into(),
+            content: content.into(),
+        }
+    }
+    pub fn private(sender: impl Into<String>, content: impl Into<String>) -> Self {
+        Self::Private {
+            sender: sender.into(),
+            content: content.into(),
+        }
+    }
+    pub fn prompt(content: impl Into<String>) -> Self {
+        Self::Prompt {
+            content: content.into(),
+        }
+    }
+    pub fn notice(content: impl Into<String>) -> Self {
+        Self::Notice {
+            content: content.into(),
+        }
+    }
+    pub fn error(content: impl ToString) -> Self {
+        Self::Error {
+            content: content.to_string(),
+        }
+    }
+    pub fn shutdown(content: impl Into<String>) -> Self {
+        Self::Shutdown {
+            content: content.into(),
+        }
+    }
+}
+
+// "bob is away" or "bob is away: lunch"
+pub(crate) fn away_status(username: &str, reason: &str) -> String {
+    if reason.is_empty() {
+        format!("{} is away", username)
+    } else {
+        format!("{} is away: {}", username, reason)
+    }
+}
+
+/// Parse `90`, `90s`, `15m` or `2h`.
+pub(crate) fn parse_duration(s: &str) -> anyhow::Result<Duration> {
+    let (n, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let n: u64 = n.parse().map_err(|_| anyhow!("Invalid duration: {}", s))?;
+    let secs = match unit {
+        "s" => Some(n),
+        "m" => n.checked_mul(60),
+        "h" => n.checked_mul(3600),
+        _ => None,
+    };
+    secs.map(Duration::from_secs)
+        .ok_or_else(|| anyhow!("Invalid duration: {}", s))
+}
+
+pub(crate) fn validate_room(room: &str) -> anyhow::Result<()> {
+    if room.is_empty() || room.contains(char::is_whitespace) {
+        return Err(anyhow!("Room name must be a single non-empty word"));
+    }
+    if room.len() > MAX_ROOM_NAME_LEN {
+        return Err(anyhow!(
+            "Room name must be at most {} characters",
+            MAX_ROOM_NAME_LEN
+        ));
+    }
+    Ok(())
+}
+
+impl FromStr for Command {
+    type Err = anyhow::Error;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let Some(line) = line.strip_prefix('/') else {
+            return Ok(Self::Chat {
+                content: line.to_string(),
+            });
+        };
+        let (cmd, args) = match line.split_once(char::is_whitespace) {
+            Some((cmd, args)) => (cmd, args.trim()),
+            None => (line, ""),
+        };
+        match cmd {
+            "join" => {
+                let room = args.trim_start_matches('#');
+                validate_room(room)?;
+                Ok(Self::Join {
+                    room: room.to_string(),
+                })
+            }
+            "leave" if args.is_empty() => Ok(Self::Leave),
+            "rooms" if args.is_empty() => Ok(Self::Rooms),
+            "who" if args.is_empty() => Ok(Self::Who),
+            "away" => Ok(Self::Away {
+                reason: (!args.is_empty()).then(|| args.to_string()),
+            }),
+            "back" if args.is_empty() => Ok(Self::Back),
+            "admin" if !args.is_empty() => Ok(Self::Admin {
+                secret: args.to_string(),
+            }),
+            "kick" if !args.is_empty() => Ok(Self::Kick {
+                user: args.to_string(),
+            }),
+            "mute" => match args.split_once(char::is_whitespace) {
+                Some((user, duration)) => Ok(Self::Mute {
+                    user: user.to_string(),
+                    secs: parse_duration(duration.trim())?.as_secs(),
+                }),
+                None => Err(anyhow!("Usage: /mute <username> <duration>")),
+            },
+            "ban" if !args.is_empty() => Ok(Self::Ban {
+                target: args.to_string(),
+            }),
+            "unban" if !args.is_empty() => Ok(Self::Unban {
+                target: args.to_string(),
+            }),
+            "admin" | "kick" | "ban" | "unban" => Err(anyhow!("Usage: /{} <argument>", cmd)),
+            "history" if args.is_empty() => Ok(Self::History { n: None }),
+            "history" => match args.parse() {
+                Ok(n) => Ok(Self::History { n: Some(n) }),
+                Err(_) => Err(anyhow!("Usage: /history <n>")),
+            },
+            "msg" => match args.split_once(char::is_whitespace) {
+                Some((to, content)) if !content.trim().is_empty() => Ok(Self::Msg {
+                    to: to.to_string(),
+                    content: content.trim().to_string(),
+                }),
+                _ => Err(anyhow!("Usage: /msg <username> <text>")),
+            },
+            "" => Err(anyhow!("Empty command")),
+            cmd => Err(anyhow!("Unknown command: /{}", cmd)),
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UserJoined { room, username } => write!(f, "[{} joined #{}]", username, room),
+            Self::UserLeft { room, username } => write!(f, "[{} left #{} :(]", username, room),
+            Self::Chat {
+                sender, content, ..
+            } => write!(f, "{}: {}", sender, content),
+            Self::Typing { username, .. } => write!(f, "[{} is typing...]", username),
+            Self::Attachment {
+                sender,
+                name,
+                size,
+                checksum,
+                ..
+            } => write!(
+                f,
+                "[{} shared {} ({} bytes, blake3 {})]",
+                sender, name, size, checksum
+            ),
+            Self::Ack { id, error: None } => write!(f, "[attachment {} received]", id),
+            Self::Ack {
+                id,
+                error: Some(error),
+            } => write!(f, "! Attachment {} failed: {}", id, error),
+            Self::Away {
+                username, reason, ..
+            } => write!(
+                f,
+                "[{}]",
+                away_status(username, reason.as_deref().unwrap_or_default())
+            ),
+            Self::Back { username, .. } => write!(f, "[{} is back]", username),
+            Self::Private { sender, content } => write!(f, "[pm] {}: {}", sender, content),
+            Self::History { event } => {
+                let timestamp = event.timestamp.format("%Y-%m-%d %H:%M:%S");
+                write!(f, "[{}] {}", timestamp, event)
+            }
+            Self::Prompt { content } => write!(f, "{}", content),
+            Self::Notice { content } => write!(f, "* {}", content),
+            Self::Error { content } => write!(f, "! {}", content),
+            Self::Shutdown { content } => write!(f, "[{}]", content),
+            // only JSON clients are pinged
+            Self::Ping => Ok(()),
+        }
+    }
+}
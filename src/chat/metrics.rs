@@ -0,0 +1,172 @@
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use axum::{extract, http::header, response::IntoResponse};
+use tokio::time::Duration;
+
+use super::state::State;
+
+// upper bounds of the fan-out latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 10] = [
+    0.000_1, 0.000_25, 0.000_5, 0.001, 0.002_5, 0.005, 0.01, 0.025, 0.05, 0.1,
+];
+
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    pub(crate) dropped_oldest: AtomicU64,
+    pub(crate) dropped_newest: AtomicU64,
+    pub(crate) slow_disconnects: AtomicU64,
+    pub(crate) rate_limited: AtomicU64,
+    pub(crate) flood_disconnects: AtomicU64,
+    // frames received from peers, the rate is computed by the scraper
+    pub(crate) messages_received: AtomicU64,
+    // events that could not be queued for or written to a peer
+    pub(crate) failed_sends: AtomicU64,
+    // how long it takes to queue a room event for every member
+    pub(crate) fan_out: Histogram,
+}
+
+/// A Prometheus histogram over [`LATENCY_BUCKETS`].
+#[derive(Debug, Default)]
+pub(crate) struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    pub(crate) fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|bound| secs <= *bound) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        // buckets are cumulative
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+/// Serve the metrics in the Prometheus text format.
+pub(crate) async fn metrics_handler(
+    extract::State(state): extract::State<Arc<State>>,
+) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        render(&state),
+    )
+}
+
+fn render(state: &State) -> String {
+    let metrics = &state.metrics;
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        let _ = writeln!(out, "{} {}", name, value);
+    };
+    let counter = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    metric(
+        "chat_peers",
+        "gauge",
+        "Connected peers.",
+        state.peers.len() as u64,
+    );
+    metric(
+        "chat_rooms",
+        "gauge",
+        "Rooms with connected members.",
+        state.rooms.len() as u64,
+    );
+    metric(
+        "chat_cluster_links",
+        "gauge",
+        "Other cluster nodes this node is linked to.",
+        state.cluster.nodes().len() as u64,
+    );
+    metric(
+        "chat_messages_received_total",
+        "counter",
+        "Messages received from peers.",
+        counter(&metrics.messages_received),
+    );
+    metric(
+        "chat_failed_sends_total",
+        "counter",
+        "Messages that could not be delivered to a peer.",
+        counter(&metrics.failed_sends),
+    );
+    metric(
+        "chat_dropped_oldest_total",
+        "counter",
+        "Queued messages dropped to make room for newer ones.",
+        counter(&metrics.dropped_oldest),
+    );
+    metric(
+        "chat_dropped_newest_total",
+        "counter",
+        "Messages dropped because the queue of the peer was full.",
+        counter(&metrics.dropped_newest),
+    );
+    metric(
+        "chat_slow_disconnects_total",
+        "counter",
+        "Peers disconnected for not keeping up.",
+        counter(&metrics.slow_disconnects),
+    );
+    metric(
+        "chat_rate_limited_total",
+        "counter",
+        "Messages that exceeded the rate limit.",
+        counter(&metrics.rate_limited),
+    );
+    metric(
+        "chat_flood_disconnects_total",
+        "counter",
+        "Peers disconnected for flooding.",
+        counter(&metrics.flood_disconnects),
+    );
+    metrics.fan_out.render(
+        &mut out,
+        "chat_broadcast_fan_out_seconds",
+        "Time to queue a room event for every member.",
+    );
+
+    let mut depths: Vec<_> = state
+        .peers
+        .iter()
+        .map(|peer| (*peer.key(), peer.value().len()))
+        .collect();
+    depths.sort();
+    let name = "chat_peer_queue_depth";
+    let _ = writeln!(
+        out,
+        "# HELP {} Messages waiting to be written to a peer.",
+        name
+    );
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    for (addr, depth) in depths {
+        let _ = writeln!(out, "{}{{peer=\"{}\"}} {}", name, addr, depth);
+    }
+    out
+}
@@ -0,0 +1,58 @@
+//! A multi-room line based chat server, reachable over plain TCP, TLS and
+//! websockets. Several servers can form a cluster whose nodes relay room
+//! events to each other.
+//!
+//! ```no_run
+//! use ecosystem::chat::{ChatServer, Config};
+//!
+//! # async fn run() -> anyhow::Result<()> {
+//! let server = ChatServer::builder().config(Config::default()).build().await?;
+//! let handle = server.handle();
+//! tokio::spawn(server.run());
+//! handle.broadcast("lobby", ecosystem::chat::Message::notice("hello")).await;
+//! # Ok(())
+//! # }
+//! ```
+
+mod cluster;
+mod config;
+mod frame;
+mod limiter;
+mod log;
+mod message;
+mod metrics;
+mod outbox;
+mod server;
+mod state;
+
+use std::pin::Pin;
+
+use futures::{stream::BoxStream, Sink};
+use tokio::time::Duration;
+
+pub use config::{
+    AttachmentConfig, ClusterConfig, Config, HeartbeatConfig, HistoryConfig, LogConfig,
+    ModerationConfig, RateLimitConfig, SlowConsumerPolicy, TlsConfig, UsernameConfig,
+};
+pub use frame::{Frame, FrameCodec};
+pub use log::read_log;
+pub use message::{Event, Message};
+pub use server::{ChatHandle, ChatServer, ChatServerBuilder, Hook};
+
+const MAX_MESSAGES: usize = 128;
+const DEFAULT_ROOM: &str = "lobby";
+const MAX_ROOM_NAME_LEN: usize = 32;
+// how long a disconnected peer gets to receive the reason
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+// a peer's strikes are forgiven after this long without a violation
+const STRIKE_RESET: Duration = Duration::from_secs(60);
+// admins can mute a peer for at most a week
+const MAX_MUTE: Duration = Duration::from_secs(7 * 24 * 3600);
+// how long a listener pauses after a failed accept
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// transport independent halves of a client connection, transports that only
+// carry lines drop the binary frames
+type FrameSink = Pin<Box<dyn Sink<Frame, Error = anyhow::Error> + Send>>;
+type FrameStream = BoxStream<'static, anyhow::Result<Frame>>;
@@ -0,0 +1,128 @@
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc, Mutex},
+};
+
+use anyhow::anyhow;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+use super::{
+    config::SlowConsumerPolicy,
+    message::{Event, Message},
+    metrics::Metrics,
+    MAX_MESSAGES,
+};
+
+/// Bounded queue of the events waiting to be written to a peer, so that
+/// a stalled peer never blocks a broadcast.
+#[derive(Debug)]
+pub(crate) struct Outbox {
+    addr: SocketAddr,
+    policy: SlowConsumerPolicy,
+    metrics: Arc<Metrics>,
+    queue: Mutex<VecDeque<Arc<Event>>>,
+    notify: Notify,
+    // the writer exits once the queue is empty
+    pub(crate) draining: CancellationToken,
+    // the writer exits right away, the queue is discarded
+    pub(crate) closed: CancellationToken,
+}
+
+impl Outbox {
+    pub(crate) fn new(addr: SocketAddr, policy: SlowConsumerPolicy, metrics: Arc<Metrics>) -> Self {
+        Self {
+            addr,
+            policy,
+            metrics,
+            queue: Mutex::new(VecDeque::with_capacity(MAX_MESSAGES)),
+            notify: Notify::new(),
+            draining: CancellationToken::new(),
+            closed: CancellationToken::new(),
+        }
+    }
+
+    /// Queue an event for the peer, applying the slow consumer policy when the
+    /// queue is full. Fails once the peer is disconnected.
+    pub(crate) fn push(&self, event: Arc<Event>) -> anyhow::Result<()> {
+        if self.closed.is_cancelled() || self.draining.is_cancelled() {
+            return Err(anyhow!("peer {} is disconnected", self.addr));
+        }
+        let mut queue = self.queue.lock().unwrap();
+        if queue.len() >= MAX_MESSAGES {
+            match self.policy {
+                SlowConsumerPolicy::DropOldest => {
+                    queue.pop_front();
+                    self.metrics.dropped_oldest.fetch_add(1, Ordering::Relaxed);
+                    warn!(
+                        "Outgoing queue of {} is full, dropped oldest message",
+                        self.addr
+                    );
+                }
+                SlowConsumerPolicy::DropNewest => {
+                    self.metrics.dropped_newest.fetch_add(1, Ordering::Relaxed);
+                    warn!(
+                        "Outgoing queue of {} is full, dropped newest message",
+                        self.addr
+                    );
+                    return Ok(());
+                }
+                SlowConsumerPolicy::Disconnect => {
+                    drop(queue);
+                    self.metrics
+                        .slow_disconnects
+                        .fetch_add(1, Ordering::Relaxed);
+                    warn!("Outgoing queue of {} is full, disconnecting", self.addr);
+                    self.close();
+                    return Err(anyhow!("peer {} is too slow", self.addr));
+                }
+            }
+        }
+        queue.push_back(event);
+        drop(queue);
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    /// Number of events waiting to be written.
+    pub(crate) fn len(&self) -> usize {
+        self.queue.lock().unwrap().len()
+    }
+
+    /// Wait for the next event to write, `None` once the peer is disconnected.
+    pub(crate) async fn recv(&self) -> Option<Arc<Event>> {
+        loop {
+            if self.closed.is_cancelled() {
+                return None;
+            }
+            if let Some(event) = self.queue.lock().unwrap().pop_front() {
+                return Some(event);
+            }
+            if self.draining.is_cancelled() {
+                return None;
+            }
+            tokio::select! {
+                _ = self.notify.notified() => {}
+                _ = self.draining.cancelled() => {}
+                _ = self.closed.cancelled() => return None,
+            }
+        }
+    }
+
+    /// Stop accepting events and let the writer exit once the queue is flushed.
+    pub(crate) fn drain(&self) {
+        self.draining.cancel();
+    }
+
+    pub(crate) fn close(&self) {
+        self.closed.cancel();
+    }
+
+    /// Tell the peer why it is being disconnected, then disconnect it.
+    pub(crate) fn disconnect(&self, reason: Message) {
+        let _ = self.push(Arc::new(reason.into()));
+        self.drain();
+    }
+}
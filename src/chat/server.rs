@@ -0,0 +1,807 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    net::SocketAddr,
+    path::Path,
+    sync::{atomic::Ordering, Arc},
+};
+
+use anyhow::{anyhow, Context};
+use axum::{
+    extract::{
+        self,
+        ws::{self, WebSocket, WebSocketUpgrade},
+        ConnectInfo,
+    },
+    response::IntoResponse,
+    routing::get,
+    Extension, Router,
+};
+use futures::{stream, SinkExt, StreamExt, TryStreamExt};
+use socket2::{SockRef, TcpKeepalive};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+    time::{Duration, Instant},
+};
+use tokio_rustls::{
+    rustls::{
+        pki_types::{CertificateDer, PrivateKeyDer},
+        server::WebPkiClientVerifier,
+        RootCertStore, ServerConfig,
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+use tokio_util::{
+    codec::{Framed, LinesCodec, LinesCodecError},
+    sync::CancellationToken,
+};
+use tracing::{info, warn};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use super::{
+    cluster,
+    config::{Config, TlsConfig},
+    frame::{Frame, FrameCodec, Uploads},
+    limiter::Verdict,
+    message::{Event, Hello, Message, Proto},
+    metrics::metrics_handler,
+    state::State,
+    FrameSink, FrameStream, ACCEPT_BACKOFF, FLUSH_TIMEOUT, TLS_HANDSHAKE_TIMEOUT,
+};
+
+/// Extension point to inspect or rewrite what peers send.
+pub trait Hook: Send + Sync + 'static {
+    /// Called for every chat or private message before it is recorded and
+    /// delivered. An error rejects the message and is sent back to the sender.
+    fn on_message(&self, message: &mut Message) -> anyhow::Result<()> {
+        let _ = message;
+        Ok(())
+    }
+
+    fn on_join(&self, room: &str, username: &str) {
+        let _ = (room, username);
+    }
+
+    fn on_leave(&self, room: &str, username: &str) {
+        let _ = (room, username);
+    }
+}
+
+/// Configures and binds a [`ChatServer`].
+#[derive(Default)]
+pub struct ChatServerBuilder {
+    config: Config,
+    listener: Option<TcpListener>,
+    tls_listener: Option<TcpListener>,
+    websocket_listener: Option<TcpListener>,
+    framed_listener: Option<TcpListener>,
+    cluster_listener: Option<TcpListener>,
+    metrics_listener: Option<TcpListener>,
+    hooks: Vec<Arc<dyn Hook>>,
+    shutdown: Option<CancellationToken>,
+}
+
+/// A bound chat server, see [`ChatServer::run`].
+pub struct ChatServer {
+    state: Arc<State>,
+    listeners: Listeners,
+    shutdown: CancellationToken,
+}
+
+/// Cheap to clone handle to a running server, for sending server-originated
+/// messages and stopping it.
+#[derive(Clone)]
+pub struct ChatHandle {
+    state: Arc<State>,
+    shutdown: CancellationToken,
+}
+
+struct Listeners {
+    tcp: TcpListener,
+    tls: Option<(TcpListener, TlsAcceptor)>,
+    websocket: Option<TcpListener>,
+    framed: Option<TcpListener>,
+    cluster: Option<TcpListener>,
+    metrics: Option<TcpListener>,
+}
+
+impl ChatServerBuilder {
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Accept plain TCP peers on this listener instead of binding
+    /// `config.listen_addr`.
+    pub fn listener(mut self, listener: TcpListener) -> Self {
+        self.listener = Some(listener);
+        self
+    }
+
+    /// Accept TLS peers on this listener instead of binding
+    /// `config.tls.listen_addr`, only used when `config.tls` is set.
+    pub fn tls_listener(mut self, listener: TcpListener) -> Self {
+        self.tls_listener = Some(listener);
+        self
+    }
+
+    /// Serve the websocket gateway on this listener instead of binding
+    /// `config.websocket_addr`.
+    pub fn websocket_listener(mut self, listener: TcpListener) -> Self {
+        self.websocket_listener = Some(listener);
+        self
+    }
+
+    /// Accept peers of the binary protocol on this listener instead of
+    /// binding `config.attachments.listen_addr`.
+    pub fn framed_listener(mut self, listener: TcpListener) -> Self {
+        self.framed_listener = Some(listener);
+        self
+    }
+
+    /// Accept links from the other nodes of the cluster on this listener
+    /// instead of binding `config.cluster.listen_addr`.
+    pub fn cluster_listener(mut self, listener: TcpListener) -> Self {
+        self.cluster_listener = Some(listener);
+        self
+    }
+
+    /// Serve the metrics on this listener instead of binding
+    /// `config.metrics_addr`.
+    pub fn metrics_listener(mut self, listener: TcpListener) -> Self {
+        self.metrics_listener = Some(listener);
+        self
+    }
+
+    /// Hooks run in the order they are added.
+    pub fn hook(mut self, hook: impl Hook) -> Self {
+        self.hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Stop the server once `token` is cancelled.
+    pub fn shutdown(mut self, token: CancellationToken) -> Self {
+        self.shutdown = Some(token);
+        self
+    }
+
+    pub async fn build(self) -> anyhow::Result<ChatServer> {
+        let config = self.config;
+        let tcp = match self.listener {
+            Some(listener) => listener,
+            None => TcpListener::bind(&config.listen_addr)
+                .await
+                .with_context(|| format!("Can not bind {}", config.listen_addr))?,
+        };
+        info!("Start chat server on {}", tcp.local_addr()?);
+        let tls = match &config.tls {
+            Some(tls) => {
+                let acceptor = tls_acceptor(tls)?;
+                let listener = match self.tls_listener {
+                    Some(listener) => listener,
+                    None => TcpListener::bind(&tls.listen_addr).await?,
+                };
+                info!("Start TLS chat server on {}", listener.local_addr()?);
+                Some((listener, acceptor))
+            }
+            None => None,
+        };
+        let websocket = match (self.websocket_listener, &config.websocket_addr) {
+            (Some(listener), _) => Some(listener),
+            (None, Some(addr)) => Some(TcpListener::bind(addr).await?),
+            (None, None) => None,
+        };
+        if let Some(websocket) = &websocket {
+            info!(
+                "Start websocket gateway on ws://{}/ws",
+                websocket.local_addr()?
+            );
+        }
+        let framed = match (self.framed_listener, &config.attachments.listen_addr) {
+            (Some(listener), _) => Some(listener),
+            (None, Some(addr)) => Some(TcpListener::bind(addr).await?),
+            (None, None) => None,
+        };
+        if let Some(framed) = &framed {
+            info!("Start binary chat server on {}", framed.local_addr()?);
+        }
+        let cluster = match (self.cluster_listener, &config.cluster.listen_addr) {
+            (Some(listener), _) => Some(listener),
+            (None, Some(addr)) => Some(
+                TcpListener::bind(addr)
+                    .await
+                    .with_context(|| format!("Can not bind {}", addr))?,
+            ),
+            (None, None) => None,
+        };
+        if let Some(cluster) = &cluster {
+            info!("Accept cluster links on {}", cluster.local_addr()?);
+        }
+        let metrics = match (self.metrics_listener, &config.metrics_addr) {
+            (Some(listener), _) => Some(listener),
+            (None, Some(addr)) => Some(TcpListener::bind(addr).await?),
+            (None, None) => None,
+        };
+        if let Some(metrics) = &metrics {
+            info!("Serve metrics on http://{}/metrics", metrics.local_addr()?);
+        }
+        Ok(ChatServer {
+            state: Arc::new(State::try_new(config, self.hooks)?),
+            listeners: Listeners {
+                tcp,
+                tls,
+                websocket,
+                framed,
+                cluster,
+                metrics,
+            },
+            shutdown: self.shutdown.unwrap_or_default(),
+        })
+    }
+}
+
+impl ChatServer {
+    pub fn builder() -> ChatServerBuilder {
+        ChatServerBuilder::default()
+    }
+
+    pub fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        Ok(self.listeners.tcp.local_addr()?)
+    }
+
+    pub fn tls_addr(&self) -> Option<SocketAddr> {
+        self.listeners.tls.as_ref()?.0.local_addr().ok()
+    }
+
+    pub fn websocket_addr(&self) -> Option<SocketAddr> {
+        self.listeners.websocket.as_ref()?.local_addr().ok()
+    }
+
+    pub fn framed_addr(&self) -> Option<SocketAddr> {
+        self.listeners.framed.as_ref()?.local_addr().ok()
+    }
+
+    pub fn cluster_addr(&self) -> Option<SocketAddr> {
+        self.listeners.cluster.as_ref()?.local_addr().ok()
+    }
+
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.listeners.metrics.as_ref()?.local_addr().ok()
+    }
+
+    pub fn handle(&self) -> ChatHandle {
+        ChatHandle {
+            state: self.state.clone(),
+            shutdown: self.shutdown.clone(),
+        }
+    }
+
+    /// Serve peers until the server is shut down.
+    pub async fn run(self) -> anyhow::Result<()> {
+        serve(self.state, self.listeners, self.shutdown).await
+    }
+}
+
+impl ChatHandle {
+    /// Deliver a message to everyone in `room`. Room events are recorded
+    /// just like the ones sent by peers.
+    pub async fn broadcast(&self, room: &str, message: Message) {
+        let event = Arc::new(Event::from(message));
+        if event.message.room() == Some(room) {
+            self.state.record(room, event.clone());
+        }
+        self.state.broadcast(room, None, event).await;
+    }
+
+    pub async fn send_to(&self, username: &str, message: Message) -> anyhow::Result<()> {
+        self.state
+            .send_to(username, Arc::new(Event::from(message)))
+            .await
+    }
+
+    /// Usernames of the connected peers.
+    pub fn users(&self) -> Vec<String> {
+        let mut users: Vec<_> = self.state.users.iter().map(|u| u.key().clone()).collect();
+        users.sort();
+        users
+    }
+
+    /// Cluster addresses of the nodes this one currently has a link to.
+    pub fn nodes(&self) -> Vec<String> {
+        self.state.cluster.nodes()
+    }
+
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+    }
+}
+
+/// Run the chat server until `shutdown` is cancelled, then say goodbye to
+/// every peer and flush their outgoing queues.
+async fn serve(
+    state: Arc<State>,
+    listeners: Listeners,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
+    let max_line_length = state.config.rate_limit.max_line_length;
+    // text peers never answer a ping, the kernel probes idle connections
+    // instead and a dead one fails the next read
+    let keepalive = Duration::from_secs(state.config.heartbeat.interval_secs.max(1));
+    let peer_listeners = [
+        Some(&listeners.tcp),
+        listeners.tls.as_ref().map(|(listener, _)| listener),
+        listeners.websocket.as_ref(),
+        listeners.framed.as_ref(),
+    ];
+    for listener in peer_listeners.into_iter().flatten() {
+        keep_alive(listener, keepalive);
+    }
+    if let Some(ws_listener) = listeners.websocket {
+        let app = Router::new()
+            .route("/ws", get(ws_handler))
+            .layer(Extension(shutdown.clone()))
+            .with_state(state.clone());
+        let service = app.into_make_service_with_connect_info::<SocketAddr>();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(ws_listener, service)
+                .with_graceful_shutdown(shutdown.cancelled_owned())
+                .await
+            {
+                warn!("Websocket gateway stopped: {}", e);
+            }
+        });
+    }
+    if let Some(metrics_listener) = listeners.metrics {
+        let app = Router::new()
+            .route("/metrics", get(metrics_handler))
+            .with_state(state.clone());
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(metrics_listener, app)
+                .with_graceful_shutdown(shutdown.cancelled_owned())
+                .await
+            {
+                warn!("Metrics endpoint stopped: {}", e);
+            }
+        });
+    }
+    if let Some((tls_listener, acceptor)) = listeners.tls {
+        let state = state.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            while let Some((stream, addr)) =
+                accept(&tls_listener, "TLS connection", &shutdown).await
+            {
+                info!("Accept TLS connection from {}", addr);
+                let state_cloned = state.clone();
+                let acceptor = acceptor.clone();
+                let shutdown = shutdown.clone();
+                state.connections.spawn(async move {
+                    let handshake =
+                        tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream));
+                    let handshake = tokio::select! {
+                        handshake = handshake => handshake,
+                        _ = shutdown.cancelled() => return,
+                    };
+                    let stream = match handshake {
+                        Ok(Ok(stream)) => stream,
+                        Ok(Err(e)) => {
+                            warn!("TLS handshake with {} failed: {}", addr, e);
+                            return;
+                        }
+                        Err(_) => {
+                            warn!("TLS handshake with {} timed out", addr);
+                            return;
+                        }
+                    };
+                    let identity = client_identity(&stream);
+                    let (sink, stream) = split_lines(stream, max_line_length);
+                    let handled =
+                        handle_client(state_cloned, addr, sink, stream, identity, shutdown);
+                    if let Err(e) = handled.await {
+                        warn!("Failed to handle TLS connection from {}: {}", addr, e);
+                    }
+                });
+            }
+        });
+    }
+    if let Some(framed_listener) = listeners.framed {
+        let state = state.clone();
+        let shutdown = shutdown.clone();
+        // room for a chunk or a line plus the frame header
+        let attachments = &state.config.attachments;
+        let max_frame_length = max_line_length.max(attachments.chunk_size) + 64;
+        tokio::spawn(async move {
+            while let Some((stream, addr)) =
+                accept(&framed_listener, "binary connection", &shutdown).await
+            {
+                info!("Accept binary connection from {}", addr);
+                let state_cloned = state.clone();
+                let shutdown = shutdown.clone();
+                state.connections.spawn(async move {
+                    let (sink, stream) = split_frames(stream, max_frame_length);
+                    let handled = handle_client(state_cloned, addr, sink, stream, None, shutdown);
+                    if let Err(e) = handled.await {
+                        warn!("Failed to handle binary connection from {}: {}", addr, e);
+                    }
+                });
+            }
+        });
+    }
+    if let Some(cluster_listener) = listeners.cluster {
+        let accept = cluster::accept_links(state.clone(), cluster_listener, shutdown.clone());
+        tokio::spawn(accept);
+    }
+    for node in &state.config.cluster.peers {
+        tokio::spawn(cluster::link(state.clone(), node.clone(), shutdown.clone()));
+    }
+    while let Some((stream, addr)) = accept(&listeners.tcp, "connection", &shutdown).await {
+        info!("Accept connection from {}", addr);
+        let state_cloned = state.clone();
+        let shutdown = shutdown.clone();
+        state.connections.spawn(async move {
+            let (sink, stream) = split_lines(stream, max_line_length);
+            let handled = handle_client(state_cloned, addr, sink, stream, None, shutdown);
+            if let Err(e) = handled.await {
+                warn!("Failed to handle connection from {}: {}", addr, e);
+            }
+        });
+    }
+    drop(listeners.tcp);
+    state.shutdown().await;
+    Ok(())
+}
+
+/// The next connection on `listener`, `None` once `shutdown` is cancelled.
+/// Failures such as running out of file descriptors are logged and retried
+/// after a pause, they don't end the listener.
+pub(crate) async fn accept(
+    listener: &TcpListener,
+    what: &str,
+    shutdown: &CancellationToken,
+) -> Option<(TcpStream, SocketAddr)> {
+    loop {
+        let e = tokio::select! {
+            conn = listener.accept() => match conn {
+                Ok(conn) => return Some(conn),
+                Err(e) => e,
+            },
+            _ = shutdown.cancelled() => return None,
+        };
+        warn!("Failed to accept {}: {}", what, e);
+        tokio::select! {
+            _ = tokio::time::sleep(ACCEPT_BACKOFF) => {}
+            _ = shutdown.cancelled() => return None,
+        }
+    }
+}
+
+/// Accepted sockets inherit TCP keepalive from their listener on Linux, the
+/// BSDs, macOS and Windows, other platforms may need it set on every socket.
+fn keep_alive(listener: &TcpListener, idle: Duration) {
+    let keepalive = TcpKeepalive::new().with_time(idle);
+    if let Err(e) = SockRef::from(listener).set_tcp_keepalive(&keepalive) {
+        warn!("Failed to enable TCP keepalive: {}", e);
+    }
+}
+
+async fn ws_handler(
+    ws: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    extract::State(state): extract::State<Arc<State>>,
+    Extension(shutdown): Extension<CancellationToken>,
+) -> impl IntoResponse {
+    info!("Accept websocket connection from {}", addr);
+    let max_line_length = state.config.rate_limit.max_line_length;
+    let connections = state.connections.clone();
+    ws.max_message_size(max_line_length)
+        .on_upgrade(move |socket: WebSocket| {
+            connections.track_future(async move {
+                let (sink, stream) = socket.split();
+                let sink = sink
+                    .sink_map_err(anyhow::Error::from)
+                    .with_flat_map(|frame| {
+                        stream::iter(text_frame(frame).map(|line| line.map(ws::Message::Text)))
+                    });
+                // only text frames carry lines, ping/pong are answered by axum itself
+                let stream = stream
+                    .try_filter_map(|message| async move {
+                        match message {
+                            ws::Message::Text(line) => Ok(Some(Frame::Text(line))),
+                            _ => Ok(None),
+                        }
+                    })
+                    .map_err(anyhow::Error::from)
+                    .boxed();
+                let handled = handle_client(state, addr, Box::pin(sink), stream, None, shutdown);
+                if let Err(e) = handled.await {
+                    warn!("Failed to handle websocket connection from {}: {}", addr, e);
+                }
+            })
+        })
+}
+
+async fn handle_client(
+    state: Arc<State>,
+    addr: SocketAddr,
+    mut sink: FrameSink,
+    mut stream: FrameStream,
+    mut identity: Option<String>,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
+    if state.bans.lock().unwrap().ips.contains(&addr.ip()) {
+        warn!("Rejected banned address {}", addr);
+        let rejection = Event::from(Message::error("You are banned"));
+        sink.send(Frame::Text(Proto::Text.encode(&rejection)))
+            .await?;
+        return Ok(());
+    }
+    let mut proto = Proto::Text;
+    let (username, outbox, verified) = loop {
+        let (line, verified) = match identity.take() {
+            // the username comes from a verified client certificate
+            Some(username) => (username, true),
+            None => {
+                let prompt = Event::from(Message::prompt("Enter your username:"));
+                sink.send(Frame::Text(proto.encode(&prompt))).await?;
+                let frame = tokio::select! {
+                    frame = stream.next() => frame,
+                    _ = shutdown.cancelled() => {
+                        let farewell = Event::from(Message::shutdown("Server is shutting down"));
+                        sink.send(Frame::Text(proto.encode(&farewell))).await?;
+                        return Ok(());
+                    }
+                };
+                match frame {
+                    Some(Ok(Frame::Text(line))) => (line, false),
+                    Some(Ok(_)) => {
+                        let rejection = Event::from(Message::error("Log in first"));
+                        sink.send(Frame::Text(proto.encode(&rejection))).await?;
+                        continue;
+                    }
+                    Some(Err(e)) => return Err(e),
+                    None => return Ok(()),
+                }
+            }
+        };
+        if proto == Proto::Text {
+            if let Ok(hello) = serde_json::from_str::<Hello>(&line) {
+                proto = hello.proto;
+                continue;
+            }
+        }
+        let rejection = match proto.decode_login(&line) {
+            Ok(username) => match state.config.username.validate(&username) {
+                Ok(()) if state.bans.lock().unwrap().users.contains(&username) => {
+                    warn!("Rejected banned user {} from {}", username, addr);
+                    let rejection = Event::from(Message::error("You are banned"));
+                    sink.send(Frame::Text(proto.encode(&rejection))).await?;
+                    return Ok(());
+                }
+                Ok(()) => match state.reserve(addr, &username) {
+                    Some(outbox) => break (username, outbox, verified),
+                    None => format!("Username {} is already taken", username),
+                },
+                Err(e) => e.to_string(),
+            },
+            Err(e) => e.to_string(),
+        };
+        let rejection = Event::from(Message::error(rejection));
+        sink.send(Frame::Text(proto.encode(&rejection))).await?;
+    };
+    let mut peer = state.add(addr, username, proto, sink, outbox);
+    // logged in after the server gathered its peers to say goodbye
+    if shutdown.is_cancelled() {
+        peer.outbox
+            .disconnect(Message::shutdown("Server is shutting down"));
+        let _ = tokio::time::timeout(FLUSH_TIMEOUT, peer.outbox.closed.cancelled()).await;
+        state.remove(addr, &peer);
+        return Ok(());
+    }
+    peer.admin = verified && state.config.moderation.admins.contains(&peer.username);
+    state.join_room(addr, &peer).await;
+    state.replay(&peer, state.config.history.replay).await;
+    let heartbeat = &state.config.heartbeat;
+    let interval = Duration::from_secs(heartbeat.interval_secs.max(1));
+    let mut last_seen = Instant::now();
+    let mut next_ping = last_seen + interval;
+    let mut uploads = Uploads::default();
+    loop {
+        let frame = tokio::select! {
+            frame = stream.next() => frame,
+            // the peer was disconnected by the server
+            _ = peer.outbox.closed.cancelled() => break,
+            // text peers can't answer a ping, TCP keepalive finds the dead
+            // ones
+            _ = tokio::time::sleep_until(next_ping), if peer.proto == Proto::Json => {
+                if heartbeat
+                    .timeout_secs
+                    .is_some_and(|timeout| last_seen.elapsed() >= Duration::from_secs(timeout))
+                {
+                    peer.outbox
+                        .disconnect(Message::error("Idle timeout, disconnecting"));
+                    break;
+                }
+                next_ping += interval;
+                peer.send(Message::Ping).await;
+                continue;
+            }
+        };
+        last_seen = Instant::now();
+        next_ping = last_seen + interval;
+        let frame = match frame {
+            Some(Ok(frame)) => frame,
+            Some(Err(e)) => {
+                if let Some(LinesCodecError::MaxLineLengthExceeded) = e.downcast_ref() {
+                    let reason = format!(
+                        "Line exceeds {} bytes, disconnecting",
+                        state.config.rate_limit.max_line_length
+                    );
+                    peer.outbox.disconnect(Message::error(reason));
+                }
+                warn!("Failed to read line from {}: {}", addr, e);
+                break;
+            }
+            None => break,
+        };
+        state
+            .metrics
+            .messages_received
+            .fetch_add(1, Ordering::Relaxed);
+        let verdict = match &frame {
+            // uploads are slowed down to their own budget, never cut short
+            Frame::AttachmentChunk { data, .. } => {
+                let delay = peer.limiter.upload_delay(data.len());
+                if !delay.is_zero() {
+                    tokio::select! {
+                        _ = tokio::time::sleep(delay) => {}
+                        _ = peer.outbox.closed.cancelled() => break,
+                    }
+                }
+                Verdict::Allow
+            }
+            Frame::AttachmentEnd { .. } | Frame::Ack { .. } => Verdict::Allow,
+            frame => peer.limiter.check(frame.len(), &state.config.rate_limit),
+        };
+        if let (Frame::AttachmentStart { id, .. }, false) = (&frame, verdict == Verdict::Allow) {
+            // refuse the whole upload rather than failing on its chunks
+            uploads.reject(*id);
+            let error = Some("Rate limited, attachment refused".to_string());
+            peer.send(Message::Ack { id: *id, error }).await;
+        }
+        match verdict {
+            Verdict::Allow => {}
+            Verdict::Muted => continue,
+            verdict => {
+                state.metrics.rate_limited.fetch_add(1, Ordering::Relaxed);
+                warn!("{} exceeded the rate limit: {:?}", addr, verdict);
+                let reason = match verdict {
+                    Verdict::Warn => "You are sending too fast, slow down".to_string(),
+                    Verdict::Mute(duration) => {
+                        format!("You are muted for {} seconds", duration.as_secs())
+                    }
+                    _ => {
+                        state
+                            .metrics
+                            .flood_disconnects
+                            .fetch_add(1, Ordering::Relaxed);
+                        peer.outbox
+                            .disconnect(Message::error("Flooding detected, disconnecting"));
+                        break;
+                    }
+                };
+                peer.send(Message::error(reason)).await;
+                continue;
+            }
+        }
+        let line = match frame {
+            Frame::Text(line) => line,
+            // the peer confirming an attachment we sent
+            Frame::Ack { .. } => continue,
+            frame => {
+                let id = frame.attachment_id().unwrap_or_default();
+                let shared = match uploads.receive(frame, &state.config.attachments) {
+                    Ok(Some(attachment)) => state.share(addr, &peer, attachment).await,
+                    Ok(None) => continue,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = &shared {
+                    warn!("Attachment {} from {} failed: {}", id, addr, e);
+                }
+                let error = shared.err().map(|e| e.to_string());
+                peer.send(Message::Ack { id, error }).await;
+                continue;
+            }
+        };
+        match peer.proto.decode(&line) {
+            Ok(command) => state.execute(addr, &mut peer, command).await,
+            Err(e) => peer.send(Message::error(e)).await,
+        }
+    }
+    if peer.outbox.draining.is_cancelled() {
+        // give the writer a chance to deliver the reason of the disconnect
+        let _ = tokio::time::timeout(FLUSH_TIMEOUT, peer.outbox.closed.cancelled()).await;
+    }
+    state.remove(addr, &peer);
+    state.leave_room(addr, &peer, &peer.room).await;
+    Ok(())
+}
+
+fn split_lines<S>(stream: S, max_line_length: usize) -> (FrameSink, FrameStream)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let codec = LinesCodec::new_with_max_length(max_line_length);
+    let (sink, stream) = Framed::new(stream, codec).split();
+    let sink = sink
+        .sink_map_err(anyhow::Error::from)
+        .with_flat_map(|frame| stream::iter(text_frame(frame)));
+    let stream = stream.map_ok(Frame::Text).map_err(anyhow::Error::from);
+    (Box::pin(sink), stream.boxed())
+}
+
+fn split_frames<S>(stream: S, max_frame_length: usize) -> (FrameSink, FrameStream)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (sink, stream) = Framed::new(stream, FrameCodec::new(max_frame_length)).split();
+    (Box::pin(sink), stream.boxed())
+}
+
+// the line of a text frame, transports that only carry lines drop the rest
+fn text_frame(frame: Frame) -> Option<anyhow::Result<String>> {
+    match frame {
+        Frame::Text(line) => Some(Ok(line)),
+        _ => None,
+    }
+}
+
+fn tls_acceptor(config: &TlsConfig) -> anyhow::Result<TlsAcceptor> {
+    let builder = ServerConfig::builder();
+    let builder = match &config.client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(client_ca)? {
+                roots.add(cert)?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+            let verifier = if config.require_client_cert {
+                verifier.build()?
+            } else {
+                verifier.allow_unauthenticated().build()?
+            };
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let server_config =
+        builder.with_single_cert(load_certs(&config.cert)?, load_key(&config.key)?)?;
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+fn load_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path)
+        .with_context(|| format!("Can not open certificate file: {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file)).collect::<Result<_, _>>()?;
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> anyhow::Result<PrivateKeyDer<'static>> {
+    let file =
+        File::open(path).with_context(|| format!("Can not open key file: {}", path.display()))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))?
+        .ok_or_else(|| anyhow!("No private key found in {}", path.display()))
+}
+
+/// The common name of the client certificate, only present when the client
+/// authenticated with a certificate signed by the configured CA.
+fn client_identity(stream: &TlsStream<TcpStream>) -> Option<String> {
+    let (_, conn) = stream.get_ref();
+    let cert = conn.peer_certificates()?.first()?;
+    let (_, cert) = X509Certificate::from_der(cert.as_ref()).ok()?;
+    let cn = cert.subject().iter_common_name().next()?.as_str().ok()?;
+    Some(cn.to_string())
+}
@@ -0,0 +1,735 @@
+use std::{
+    collections::{BTreeSet, HashSet, VecDeque},
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::{anyhow, Context};
+use dashmap::{mapref::entry::Entry, DashMap};
+use futures::{stream, SinkExt};
+use serde::{Deserialize, Serialize};
+use tokio::time::{Duration, Instant};
+use tokio_util::task::TaskTracker;
+use tracing::{info, warn};
+
+use super::{
+    cluster::{Cluster, Envelope},
+    config::Config,
+    frame::{Attachment, Frame},
+    limiter::RateLimiter,
+    log::{read_log, ChatLog},
+    message::{away_status, Command, Event, Message, Proto},
+    metrics::Metrics,
+    outbox::Outbox,
+    server::Hook,
+    FrameSink, DEFAULT_ROOM, MAX_MUTE,
+};
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct BanList {
+    pub(crate) users: BTreeSet<String>,
+    pub(crate) ips: BTreeSet<IpAddr>,
+}
+
+#[derive(Default)]
+pub(crate) struct State {
+    pub(crate) config: Config,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) peers: DashMap<SocketAddr, Arc<Outbox>>,
+    // room name -> addresses of the members
+    pub(crate) rooms: DashMap<String, HashSet<SocketAddr>>,
+    // username -> outbox of the peer, used to address a single user and to keep
+    // the username reserved while the peer is connected
+    pub(crate) users: DashMap<String, Arc<Outbox>>,
+    // username -> what the others see in /who
+    pub(crate) presence: DashMap<String, Presence>,
+    // username -> when the mute by an admin ends, kept across reconnects
+    pub(crate) mutes: DashMap<String, Instant>,
+    pub(crate) bans: Mutex<BanList>,
+    // one save of the ban list at a time, so that an older list never wins
+    pub(crate) saving_bans: tokio::sync::Mutex<()>,
+    // room name -> the most recent chat messages, oldest first
+    pub(crate) history: DashMap<String, VecDeque<Arc<Event>>>,
+    pub(crate) next_peer_id: AtomicU64,
+    // the writer task of every connected peer
+    pub(crate) writers: TaskTracker,
+    // the task of every client connection, logging in or logged in
+    pub(crate) connections: TaskTracker,
+    pub(crate) log: Option<ChatLog>,
+    pub(crate) hooks: Vec<Arc<dyn Hook>>,
+    pub(crate) cluster: Cluster,
+}
+
+#[derive(Debug)]
+pub(crate) struct Presence {
+    pub(crate) room: String,
+    pub(crate) away: Option<String>,
+    pub(crate) last_active: Instant,
+}
+
+#[derive(Debug)]
+pub(crate) struct Peer {
+    pub(crate) id: u64,
+    pub(crate) username: String,
+    pub(crate) room: String,
+    pub(crate) proto: Proto,
+    pub(crate) outbox: Arc<Outbox>,
+    pub(crate) limiter: RateLimiter,
+    pub(crate) admin: bool,
+}
+
+impl State {
+    pub(crate) fn try_new(config: Config, hooks: Vec<Arc<dyn Hook>>) -> anyhow::Result<Self> {
+        let log = if config.log.enabled {
+            Some(ChatLog::open(&config.log)?)
+        } else {
+            None
+        };
+        let bans = match &config.moderation.ban_file {
+            Some(path) => load_bans(path)?,
+            None => BanList::default(),
+        };
+        let state = Self {
+            cluster: Cluster::new(&config.cluster),
+            config,
+            log,
+            bans: Mutex::new(bans),
+            hooks,
+            ..Default::default()
+        };
+        if let Some(log) = &state.log {
+            // bring back the history and the rooms from before the restart
+            let events = read_log(log.dir())?;
+            info!(
+                "Restoring {} events from {}",
+                events.len(),
+                log.dir().display()
+            );
+            for event in events {
+                // keep sender ids unique across restarts
+                state
+                    .next_peer_id
+                    .fetch_max(event.sender_id.unwrap_or(0), Ordering::Relaxed);
+                if let Some(room) = event.message.room().map(str::to_string) {
+                    state.remember(&room, Arc::new(event));
+                }
+            }
+        }
+        Ok(state)
+    }
+
+    pub(crate) async fn execute(&self, addr: SocketAddr, peer: &mut Peer, command: Command) {
+        if !matches!(command, Command::Pong) {
+            if let Some(mut presence) = self.presence.get_mut(&peer.username) {
+                presence.last_active = Instant::now();
+            }
+        }
+        match command {
+            Command::Chat { .. } | Command::Msg { .. } | Command::Typing
+                if self.muted(&peer.username).is_some() =>
+            {
+                let remaining = self.muted(&peer.username).unwrap_or_default();
+                let message = format!("You are muted for {} more seconds", remaining.as_secs());
+                peer.send(Message::error(message)).await;
+            }
+            Command::Kick { .. }
+            | Command::Mute { .. }
+            | Command::Ban { .. }
+            | Command::Unban { .. }
+                if !peer.admin =>
+            {
+                peer.send(Message::error("Only admins can do that")).await;
+            }
+            Command::Chat { content } => {
+                let mut message = Message::chat(&peer.room, &peer.username, content);
+                if let Err(e) = self.run_hooks(&mut message) {
+                    return peer.send(Message::error(e)).await;
+                }
+                let event = peer.event(message);
+                self.record(&peer.room, event.clone());
+                self.broadcast(&peer.room, Some(addr), event).await;
+            }
+            Command::Join { room } if room == peer.room => {
+                let message = format!("You are already in #{}", room);
+                peer.send(Message::error(message)).await;
+            }
+            Command::Join { room } => self.switch_room(addr, peer, room).await,
+            Command::Leave if peer.room == DEFAULT_ROOM => {
+                let message = format!("You can't leave #{}", DEFAULT_ROOM);
+                peer.send(Message::error(message)).await;
+            }
+            Command::Leave => self.switch_room(addr, peer, DEFAULT_ROOM.to_string()).await,
+            Command::Rooms => {
+                let message = Message::notice(self.list_rooms());
+                peer.send(message).await;
+            }
+            Command::Msg { to, content } => {
+                let mut message = Message::private(&peer.username, content);
+                if let Err(e) = self.run_hooks(&mut message) {
+                    return peer.send(Message::error(e)).await;
+                }
+                let event = peer.event(message);
+                if let Err(e) = self.send_to(&to, event).await {
+                    peer.send(Message::error(e)).await;
+                    return;
+                }
+                let away = self
+                    .presence
+                    .get(&to)
+                    .and_then(|presence| presence.away.clone());
+                if let Some(reason) = away {
+                    peer.send(Message::notice(away_status(&to, &reason))).await;
+                }
+            }
+            Command::Who => peer.send(Message::notice(self.list_users())).await,
+            Command::Away { reason } => {
+                if let Some(mut presence) = self.presence.get_mut(&peer.username) {
+                    presence.away = Some(reason.clone().unwrap_or_default());
+                }
+                peer.send(Message::notice("You are now marked as away"))
+                    .await;
+                let event = peer.event(Message::Away {
+                    room: peer.room.clone(),
+                    username: peer.username.clone(),
+                    reason,
+                });
+                self.broadcast(&peer.room, Some(addr), event).await;
+            }
+            Command::Back => {
+                let was_away = self
+                    .presence
+                    .get_mut(&peer.username)
+                    .and_then(|mut presence| presence.away.take())
+                    .is_some();
+                if !was_away {
+                    peer.send(Message::error("You are not away")).await;
+                    return;
+                }
+                peer.send(Message::notice("Welcome back")).await;
+                let event = peer.event(Message::Back {
+                    room: peer.room.clone(),
+                    username: peer.username.clone(),
+                });
+                self.broadcast(&peer.room, Some(addr), event).await;
+            }
+            Command::Typing => {
+                let event = peer.event(Message::Typing {
+                    room: peer.room.clone(),
+                    username: peer.username.clone(),
+                });
+                self.broadcast(&peer.room, Some(addr), event).await;
+            }
+            Command::Pong => {}
+            Command::Admin { secret } => {
+                if self.config.moderation.secret.as_ref() == Some(&secret) {
+                    info!("{} is now an admin", peer.username);
+                    peer.admin = true;
+                    peer.send(Message::notice("You are now an admin")).await;
+                } else {
+                    warn!("{} sent a wrong admin secret", peer.username);
+                    peer.send(Message::error("Wrong secret")).await;
+                }
+            }
+            Command::Kick { user } => {
+                let Some(outbox) = self.users.get(&user).map(|outbox| outbox.clone()) else {
+                    let message = format!("User {} is not connected", user);
+                    return peer.send(Message::error(message)).await;
+                };
+                info!("{} kicked {}", peer.username, user);
+                let reason = format!("You were kicked by {}", peer.username);
+                outbox.disconnect(Message::error(reason));
+                peer.send(Message::notice(format!("Kicked {}", user))).await;
+            }
+            Command::Mute { user, secs } => {
+                let duration = Duration::from_secs(secs);
+                // JSON peers send the seconds directly, so this is checked here
+                let Some(until) = Instant::now()
+                    .checked_add(duration)
+                    .filter(|_| duration <= MAX_MUTE)
+                else {
+                    let message = format!(
+                        "Mute duration must be at most {} seconds",
+                        MAX_MUTE.as_secs()
+                    );
+                    return peer.send(Message::error(message)).await;
+                };
+                if !self.users.contains_key(&user) {
+                    let message = format!("User {} is not connected", user);
+                    return peer.send(Message::error(message)).await;
+                }
+                self.mutes.insert(user.clone(), until);
+                info!("{} muted {} for {}s", peer.username, user, secs);
+                let message = format!("You were muted by {} for {} seconds", peer.username, secs);
+                let _ = self
+                    .send_to(&user, Arc::new(Message::error(message).into()))
+                    .await;
+                let message = format!("Muted {} for {} seconds", user, secs);
+                peer.send(Message::notice(message)).await;
+            }
+            Command::Ban { target } => {
+                let reason = format!("You were banned by {}", peer.username);
+                let outboxes: Vec<_> = match target.parse::<IpAddr>() {
+                    Ok(ip) => {
+                        self.bans.lock().unwrap().ips.insert(ip);
+                        self.peers
+                            .iter()
+                            .filter(|entry| entry.key().ip() == ip)
+                            .map(|entry| entry.value().clone())
+                            .collect()
+                    }
+                    Err(_) => {
+                        self.bans.lock().unwrap().users.insert(target.clone());
+                        self.users
+                            .get(&target)
+                            .map(|outbox| outbox.clone())
+                            .into_iter()
+                            .collect()
+                    }
+                };
+                for outbox in outboxes {
+                    outbox.disconnect(Message::error(&reason));
+                }
+                info!("{} banned {}", peer.username, target);
+                self.save_bans(peer, format!("Banned {}", target)).await;
+            }
+            Command::Unban { target } => {
+                let removed = {
+                    let mut bans = self.bans.lock().unwrap();
+                    match target.parse::<IpAddr>() {
+                        Ok(ip) => bans.ips.remove(&ip),
+                        Err(_) => bans.users.remove(&target),
+                    }
+                };
+                if !removed {
+                    let message = format!("{} is not banned", target);
+                    return peer.send(Message::error(message)).await;
+                }
+                info!("{} unbanned {}", peer.username, target);
+                self.save_bans(peer, format!("Unbanned {}", target)).await;
+            }
+            Command::History { n } => {
+                let n = n.unwrap_or(self.config.history.replay);
+                self.replay(peer, n).await;
+            }
+        }
+    }
+
+    /// How much longer an admin muted the user, if at all.
+    fn muted(&self, username: &str) -> Option<Duration> {
+        let until = *self.mutes.get(username)?;
+        let remaining = until.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            // forget expired mutes, unless a new one was set meanwhile
+            self.mutes
+                .remove_if(username, |_, current| *current == until);
+            return None;
+        }
+        Some(remaining)
+    }
+
+    /// Write the ban list to disk and tell the admin how it went.
+    async fn save_bans(&self, peer: &Peer, done: String) {
+        let Some(path) = &self.config.moderation.ban_file else {
+            return peer.send(Message::notice(done)).await;
+        };
+        let _saving = self.saving_bans.lock().await;
+        let content = serde_json::to_string_pretty(&*self.bans.lock().unwrap())
+            .expect("ban list is always serializable");
+        // write a temporary file first so that a crash never truncates the list
+        let tmp = path.with_extension("tmp");
+        let saved = async {
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            tokio::fs::write(&tmp, content).await?;
+            tokio::fs::rename(&tmp, path).await
+        };
+        match saved.await {
+            Ok(()) => peer.send(Message::notice(done)).await,
+            Err(e) => {
+                warn!("Failed to save ban list to {}: {}", path.display(), e);
+                let message = format!("{}, but failed to save the ban list", done);
+                peer.send(Message::error(message)).await;
+            }
+        }
+    }
+
+    async fn switch_room(&self, addr: SocketAddr, peer: &mut Peer, room: String) {
+        let old_room = std::mem::replace(&mut peer.room, room);
+        self.leave_room(addr, peer, &old_room).await;
+        self.join_room(addr, peer).await;
+        let message = format!("You are now in #{}", peer.room);
+        peer.send(Message::notice(message)).await;
+        self.replay(peer, self.config.history.replay).await;
+    }
+
+    pub(crate) async fn join_room(&self, addr: SocketAddr, peer: &Peer) {
+        let room = &peer.room;
+        self.rooms.entry(room.clone()).or_default().insert(addr);
+        if let Some(mut presence) = self.presence.get_mut(&peer.username) {
+            presence.room = room.clone();
+        }
+        let event = peer.event(Message::user_joined(room, &peer.username));
+        info!("{}", event);
+        self.hooks
+            .iter()
+            .for_each(|hook| hook.on_join(room, &peer.username));
+        self.record(room, event.clone());
+        self.broadcast(room, Some(addr), event).await;
+    }
+
+    pub(crate) async fn leave_room(&self, addr: SocketAddr, peer: &Peer, room: &str) {
+        self.rooms.remove_if_mut(room, |_, members| {
+            members.remove(&addr);
+            members.is_empty()
+        });
+        let event = peer.event(Message::user_left(room, &peer.username));
+        info!("{}", event);
+        self.hooks
+            .iter()
+            .for_each(|hook| hook.on_leave(room, &peer.username));
+        self.record(room, event.clone());
+        self.broadcast(room, Some(addr), event).await;
+    }
+
+    /// Share a verified attachment with the room of the peer.
+    pub(crate) async fn share(
+        &self,
+        addr: SocketAddr,
+        peer: &Peer,
+        attachment: Attachment,
+    ) -> anyhow::Result<()> {
+        if let Some(remaining) = self.muted(&peer.username) {
+            return Err(anyhow!(
+                "You are muted for {} more seconds",
+                remaining.as_secs()
+            ));
+        }
+        let mut message = Message::Attachment {
+            room: peer.room.clone(),
+            sender: peer.username.clone(),
+            name: attachment.name,
+            size: attachment.data.len(),
+            checksum: attachment.checksum,
+            data: attachment.data,
+        };
+        self.run_hooks(&mut message)?;
+        let event = peer.event(message);
+        info!("{}", event);
+        self.record(&peer.room, event.clone());
+        self.broadcast(&peer.room, Some(addr), event).await;
+        Ok(())
+    }
+
+    /// Let every hook inspect or rewrite a message sent by a peer.
+    fn run_hooks(&self, message: &mut Message) -> anyhow::Result<()> {
+        self.hooks
+            .iter()
+            .try_for_each(|hook| hook.on_message(message))
+    }
+
+    /// Persist a room event and keep it in the history if it is a chat message.
+    pub(crate) fn record(&self, room: &str, event: Arc<Event>) {
+        if let Some(log) = &self.log {
+            log.append(event.clone());
+        }
+        self.remember(room, event);
+    }
+
+    fn remember(&self, room: &str, event: Arc<Event>) {
+        // every room that ever had an event is known, even without chat messages
+        let mut history = self.history.entry(room.to_string()).or_default();
+        let capacity = self.config.history.capacity;
+        if capacity == 0 || !matches!(event.message, Message::Chat { .. }) {
+            return;
+        }
+        if history.len() == capacity {
+            history.pop_front();
+        }
+        history.push_back(event);
+    }
+
+    pub(crate) async fn replay(&self, peer: &Peer, n: usize) {
+        let events: Vec<_> = match self.history.get(&peer.room) {
+            Some(history) => history
+                .iter()
+                .skip(history.len().saturating_sub(n))
+                .cloned()
+                .collect(),
+            None => return,
+        };
+        for event in events {
+            peer.send(Message::History { event }).await;
+        }
+    }
+
+    fn list_rooms(&self) -> String {
+        let mut rooms: Vec<_> = self
+            .rooms
+            .iter()
+            .map(|room| format!("#{} ({})", room.key(), room.value().len()))
+            .collect();
+        rooms.sort();
+        let mut idle: Vec<_> = self
+            .history
+            .iter()
+            .filter(|room| !self.rooms.contains_key(room.key()))
+            .map(|room| format!("#{}", room.key()))
+            .collect();
+        idle.sort();
+        if idle.is_empty() {
+            format!("Active rooms: {}", rooms.join(", "))
+        } else {
+            format!(
+                "Active rooms: {}; idle rooms: {}",
+                rooms.join(", "),
+                idle.join(", ")
+            )
+        }
+    }
+
+    fn list_users(&self) -> String {
+        let mut users: Vec<_> = self
+            .presence
+            .iter()
+            .map(|user| {
+                let presence = user.value();
+                let status = match presence.away.as_deref() {
+                    Some("") => format!("#{}, away", presence.room),
+                    Some(reason) => format!("#{}, away: {}", presence.room, reason),
+                    None => format!("#{}", presence.room),
+                };
+                let idle = presence.last_active.elapsed().as_secs();
+                format!(
+                    "{} ({}, idle {}m{:02}s)",
+                    user.key(),
+                    status,
+                    idle / 60,
+                    idle % 60
+                )
+            })
+            .collect();
+        users.sort();
+        format!("Online users: {}", users.join(", "))
+    }
+
+    pub(crate) async fn send_to(&self, username: &str, event: Arc<Event>) -> anyhow::Result<()> {
+        let outbox = self
+            .users
+            .get(username)
+            .map(|outbox| outbox.clone())
+            .ok_or_else(|| anyhow!("User {} is not connected", username))?;
+        outbox
+            .push(event)
+            .map_err(|_| anyhow!("User {} is not connected", username))
+    }
+
+    /// Deliver an event to every member of a room except `except`, on this
+    /// node and on the others of the cluster.
+    pub(crate) async fn broadcast(
+        &self,
+        room: &str,
+        except: Option<SocketAddr>,
+        event: Arc<Event>,
+    ) {
+        self.cluster.publish(room, &event);
+        self.deliver(room, except, event).await;
+    }
+
+    /// Handle a message relayed by another node, passing it on to the nodes
+    /// linked to this one.
+    pub(crate) async fn relay(&self, mut envelope: Envelope) {
+        if !self.cluster.first_seen(&envelope.id) {
+            return;
+        }
+        // sender ids are only unique on the node that handed them out
+        if let Some(event) = Arc::get_mut(&mut envelope.event) {
+            event.sender_id = None;
+        }
+        let envelope = Arc::new(envelope);
+        let room = &envelope.room;
+        if envelope.event.message.room() == Some(room) {
+            self.record(room, envelope.event.clone());
+        }
+        self.deliver(room, None, envelope.event.clone()).await;
+        self.cluster.forward(&envelope);
+    }
+
+    /// Deliver an event to the members of a room connected to this node.
+    async fn deliver(&self, room: &str, except: Option<SocketAddr>, event: Arc<Event>) {
+        let start = Instant::now();
+        // snapshot the members so that no map guard is held while delivering
+        let members: Vec<_> = match self.rooms.get(room) {
+            Some(members) => members.iter().copied().collect(),
+            None => return,
+        };
+        for member in members {
+            if Some(member) == except {
+                continue;
+            }
+            let Some(outbox) = self.peers.get(&member).map(|outbox| outbox.clone()) else {
+                continue;
+            };
+            if let Err(e) = outbox.push(event.clone()) {
+                warn!("Failed to send message to {}: {}", member, e);
+                self.metrics.failed_sends.fetch_add(1, Ordering::Relaxed);
+
+                self.peers.remove(&member);
+                self.rooms.remove_if_mut(room, |_, members| {
+                    members.remove(&member);
+                    members.is_empty()
+                });
+            }
+        }
+        self.metrics.fan_out.observe(start.elapsed());
+    }
+
+    pub(crate) async fn shutdown(&self) {
+        let outboxes: Vec<_> = self.peers.iter().map(|peer| peer.value().clone()).collect();
+        info!("Shutting down, flushing {} peers", outboxes.len());
+        let event = Arc::new(Event::from(Message::shutdown("Server is shutting down")));
+        for outbox in &outboxes {
+            let _ = outbox.push(event.clone());
+            outbox.drain();
+        }
+        self.writers.close();
+        self.connections.close();
+        let timeout = Duration::from_secs(self.config.shutdown_timeout_secs);
+        let done = async {
+            self.writers.wait().await;
+            self.connections.wait().await;
+        };
+        if tokio::time::timeout(timeout, done).await.is_err() {
+            warn!("Timed out flushing outgoing queues, closing remaining peers");
+            outboxes.iter().for_each(|outbox| outbox.close());
+        }
+        if let Some(log) = &self.log {
+            log.close().await;
+        }
+    }
+
+    pub(crate) fn remove(&self, addr: SocketAddr, peer: &Peer) {
+        peer.outbox.close();
+        self.peers.remove(&addr);
+        let removed = self.users.remove_if(&peer.username, |_, outbox| {
+            Arc::ptr_eq(outbox, &peer.outbox)
+        });
+        if removed.is_some() {
+            self.presence.remove(&peer.username);
+        }
+    }
+
+    fn next_peer_id(&self) -> u64 {
+        self.next_peer_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub(crate) fn reserve(&self, addr: SocketAddr, username: &str) -> Option<Arc<Outbox>> {
+        match self.users.entry(username.to_string()) {
+            Entry::Occupied(_) => None,
+            Entry::Vacant(entry) => {
+                let outbox = Arc::new(Outbox::new(
+                    addr,
+                    self.config.slow_consumer,
+                    self.metrics.clone(),
+                ));
+                entry.insert(outbox.clone());
+                Some(outbox)
+            }
+        }
+    }
+
+    pub(crate) fn add(
+        &self,
+        addr: SocketAddr,
+        username: String,
+        proto: Proto,
+        mut sink: FrameSink,
+        outbox: Arc<Outbox>,
+    ) -> Peer {
+        self.peers.insert(addr, outbox.clone());
+        self.presence.insert(
+            username.clone(),
+            Presence {
+                room: DEFAULT_ROOM.to_string(),
+                away: None,
+                last_active: Instant::now(),
+            },
+        );
+
+        let writer = outbox.clone();
+        let chunk_size = self.config.attachments.chunk_size;
+        let metrics = self.metrics.clone();
+        self.writers.spawn(async move {
+            // ids of the attachments sent to the peer
+            let mut next_id = 0;
+            while let Some(event) = writer.recv().await {
+                if !proto.wants(&event) {
+                    continue;
+                }
+                let frames = match &event.message {
+                    Message::Ack { id, error } => vec![Frame::Ack {
+                        id: *id,
+                        error: error.clone(),
+                    }],
+                    // the announcement is followed by the content
+                    Message::Attachment {
+                        name,
+                        data,
+                        checksum,
+                        ..
+                    } => {
+                        next_id += 1;
+                        let mut frames = vec![Frame::Text(proto.encode(&event))];
+                        frames.extend(Frame::attachment(next_id, name, data, checksum, chunk_size));
+                        frames
+                    }
+                    _ => vec![Frame::Text(proto.encode(&event))],
+                };
+                let mut frames = stream::iter(frames.into_iter().map(Ok));
+                if let Err(e) = sink.send_all(&mut frames).await {
+                    warn!("Failed to send message to {}: {}", addr, e);
+                    metrics.failed_sends.fetch_add(1, Ordering::Relaxed);
+                    break;
+                }
+            }
+            writer.close();
+            let _ = sink.close().await;
+        });
+        Peer {
+            id: self.next_peer_id(),
+            username,
+            room: DEFAULT_ROOM.to_string(),
+            proto,
+            outbox,
+            limiter: RateLimiter::new(&self.config.rate_limit, &self.config.attachments),
+            admin: false,
+        }
+    }
+}
+
+impl Peer {
+    /// Wrap a message originated by this peer into an event.
+    pub(crate) fn event(&self, message: Message) -> Arc<Event> {
+        Arc::new(Event::new(Some(self.id), message))
+    }
+
+    pub(crate) async fn send(&self, message: Message) {
+        if let Err(e) = self.outbox.push(Arc::new(message.into())) {
+            warn!("Failed to send message to {}: {}", self.username, e);
+        }
+    }
+}
+
+fn load_bans(path: &Path) -> anyhow::Result<BanList> {
+    match fs::read_to_string(path) {
+        Ok(content) => serde_json::from_str(&content)
+            .with_context(|| format!("Invalid ban file: {}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BanList::default()),
+        Err(e) => Err(e.into()),
+    }
+}
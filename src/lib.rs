@@ -1 +1 @@
-// empty file
+pub mod chat;
@@ -0,0 +1,846 @@
+use std::{net::SocketAddr, path::Path, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use ecosystem::chat::{
+    ChatHandle, ChatServer, Config, Frame, FrameCodec, SlowConsumerPolicy, TlsConfig,
+};
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tokio_rustls::{
+    rustls::{
+        pki_types::{CertificateDer, ServerName},
+        ClientConfig, RootCertStore,
+    },
+    TlsConnector,
+};
+use tokio_util::codec::{Framed, LinesCodec};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A scripted client speaking the binary protocol.
+struct FramedClient {
+    username: String,
+    framed: Framed<TcpStream, FrameCodec>,
+}
+
+/// A scripted client speaking the text protocol.
+struct Client {
+    username: String,
+    framed: Framed<TcpStream, LinesCodec>,
+    // lines replayed from the history of the lobby on login
+    history: Vec<String>,
+}
+
+#[tokio::test]
+async fn peers_see_join_chat_and_leave_in_order() -> Result<()> {
+    let (addr, _handle) = start(test_config()).await?;
+    let mut alice = Client::connect(addr, "alice").await?;
+    let mut bob = Client::connect(addr, "bob").await?;
+    alice.expect("[bob joined #lobby]").await?;
+
+    bob.send("hi alice").await?;
+    alice.expect("bob: hi alice").await?;
+    alice.send("hi bob").await?;
+    bob.expect("alice: hi bob").await?;
+
+    let mut carol = Client::connect(addr, "carol").await?;
+    assert_eq!(carol.history.len(), 2);
+    assert!(carol.history[0].ends_with("] bob: hi alice"));
+    assert!(carol.history[1].ends_with("] alice: hi bob"));
+    alice.expect("[carol joined #lobby]").await?;
+    bob.expect("[carol joined #lobby]").await?;
+
+    bob.quit().await;
+    alice.expect("[bob left #lobby :(]").await?;
+    carol.expect("[bob left #lobby :(]").await?;
+    carol.send("bye").await?;
+    alice.expect("carol: bye").await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn newcomers_get_the_room_history() -> Result<()> {
+    let (addr, _handle) = start(test_config()).await?;
+    let mut alice = Client::connect(addr, "alice").await?;
+    alice.send("/join dev").await?;
+    alice.expect("* You are now in #dev").await?;
+    alice.send("first").await?;
+    alice.send("second").await?;
+    alice.sync().await?;
+
+    let mut bob = Client::connect(addr, "bob").await?;
+    bob.send("/join dev").await?;
+    bob.expect("* You are now in #dev").await?;
+    assert!(bob.recv().await?.ends_with("] alice: first"));
+    assert!(bob.recv().await?.ends_with("] alice: second"));
+    alice.expect("[bob joined #dev]").await?;
+
+    bob.send("/leave").await?;
+    alice.expect("[bob left #dev :(]").await?;
+    bob.expect("* You are now in #lobby").await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn history_survives_a_restart() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("chat-test-log-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let mut config = test_config();
+    config.log.enabled = true;
+    config.log.dir = dir.clone();
+    let server = ChatServer::builder()
+        .config(config)
+        .listener(TcpListener::bind("127.0.0.1:0").await?)
+        .build()
+        .await?;
+    let addr = server.local_addr()?;
+    let handle = server.handle();
+    let running = tokio::spawn(server.run());
+    let mut alice = Client::connect(addr, "alice").await?;
+    alice.send("before the restart").await?;
+    alice.sync().await?;
+    // the log is written out before the server is done
+    handle.shutdown();
+    running.await??;
+
+    let mut config = test_config();
+    config.log.enabled = true;
+    config.log.dir = dir.clone();
+    let (addr, _handle) = start(config).await?;
+    let bob = Client::connect(addr, "bob").await?;
+    assert_eq!(bob.history.len(), 1);
+    assert!(bob.history[0].ends_with("] alice: before the restart"));
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn shutdown_reaches_connections_still_logging_in() -> Result<()> {
+    let server = ChatServer::builder()
+        .config(test_config())
+        .listener(TcpListener::bind("127.0.0.1:0").await?)
+        .build()
+        .await?;
+    let addr = server.local_addr()?;
+    let handle = server.handle();
+    let running = tokio::spawn(server.run());
+    let _alice = Client::connect(addr, "alice").await?;
+    let mut pending = Framed::new(TcpStream::connect(addr).await?, LinesCodec::new());
+    assert_eq!(
+        tokio::time::timeout(TIMEOUT, pending.next())
+            .await?
+            .unwrap()?,
+        "Enter your username:"
+    );
+
+    handle.shutdown();
+    tokio::time::timeout(TIMEOUT, running).await???;
+    // the connection was closed before the server was done
+    let mut lines = Vec::new();
+    while let Some(line) = tokio::time::timeout(TIMEOUT, pending.next()).await? {
+        lines.push(line?);
+    }
+    assert_eq!(lines, ["[Server is shutting down]"]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn disconnect_mid_broadcast_keeps_the_others_in_order() -> Result<()> {
+    let (addr, _handle) = start(test_config()).await?;
+    let mut alice = Client::connect(addr, "alice").await?;
+    let mut bob = Client::connect(addr, "bob").await?;
+    alice.expect("[bob joined #lobby]").await?;
+    let mut carol = Client::connect(addr, "carol").await?;
+    alice.expect("[carol joined #lobby]").await?;
+    bob.expect("[carol joined #lobby]").await?;
+
+    let sender = tokio::spawn(async move {
+        for i in 0..100 {
+            carol.send(&format!("message {}", i)).await?;
+        }
+        anyhow::Ok(carol)
+    });
+    // bob goes away while carol is still talking
+    bob.expect("carol: message 0").await?;
+    bob.quit().await;
+
+    let mut next = 0;
+    let mut left = false;
+    while next < 100 || !left {
+        let line = alice.recv().await?;
+        if line == "[bob left #lobby :(]" {
+            assert!(!left, "bob left twice");
+            left = true;
+        } else {
+            assert_eq!(line, format!("carol: message {}", next));
+            next += 1;
+        }
+    }
+    let mut carol = sender.await??;
+    carol.expect("[bob left #lobby :(]").await?;
+    carol.send("/who").await?;
+    carol.expect_prefix("* Online users: alice ").await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn failed_sends_remove_the_peer() -> Result<()> {
+    let mut config = test_config();
+    config.slow_consumer = SlowConsumerPolicy::Disconnect;
+    let (addr, handle) = start(config).await?;
+    let mut alice = Client::connect(addr, "alice").await?;
+    // bob never reads, so his outgoing queue fills up once the socket buffers do
+    let mut bob = Client::connect(addr, "bob").await?;
+    alice.expect("[bob joined #lobby]").await?;
+
+    let line = "x".repeat(4000);
+    let mut removed = false;
+    for _ in 0..10_000 {
+        alice.send(&line).await?;
+        if !handle.users().contains(&"bob".to_string()) {
+            removed = true;
+            break;
+        }
+    }
+    assert!(removed, "bob was never disconnected");
+    alice.expect("[bob left #lobby :(]").await?;
+    assert_eq!(handle.users(), vec!["alice".to_string()]);
+
+    // whatever bob got is followed by the end of the stream
+    while tokio::time::timeout(TIMEOUT, bob.framed.next())
+        .await?
+        .is_some()
+    {}
+    Ok(())
+}
+
+#[tokio::test]
+async fn private_messages_reach_only_the_recipient() -> Result<()> {
+    let (addr, _handle) = start(test_config()).await?;
+    let mut alice = Client::connect(addr, "alice").await?;
+    let mut bob = Client::connect(addr, "bob").await?;
+    alice.expect("[bob joined #lobby]").await?;
+    let mut carol = Client::connect(addr, "carol").await?;
+    alice.expect("[carol joined #lobby]").await?;
+
+    alice.send("/msg bob psst").await?;
+    bob.expect("[carol joined #lobby]").await?;
+    bob.expect("[pm] alice: psst").await?;
+    alice.send("/msg dave hello").await?;
+    alice.expect("! User dave is not connected").await?;
+
+    // carol sees the next chat message, not the private one
+    alice.send("done").await?;
+    carol.expect("alice: done").await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn idle_text_peers_stay_until_they_quit() -> Result<()> {
+    let mut config = test_config();
+    config.heartbeat.interval_secs = 1;
+    config.heartbeat.timeout_secs = Some(1);
+    let (addr, handle) = start(config).await?;
+    let mut alice = Client::connect(addr, "alice").await?;
+    let bob = Client::connect(addr, "bob").await?;
+    alice.expect("[bob joined #lobby]").await?;
+    // idle past the heartbeat, text peers are neither pinged nor timed out
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    assert_eq!(handle.users(), vec!["alice".to_string(), "bob".to_string()]);
+
+    bob.quit().await;
+    alice.expect("[bob left #lobby :(]").await?;
+    assert_eq!(handle.users(), vec!["alice".to_string()]);
+    alice.send("/rooms").await?;
+    alice.expect("* Active rooms: #lobby (1)").await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn accepted_sockets_get_tcp_keepalive() -> Result<()> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    listener.set_nonblocking(true)?;
+    let addr = listener.local_addr()?;
+    // shares the listening socket with the server and outlives it
+    let probe = listener.try_clone()?;
+    let server = ChatServer::builder()
+        .config(test_config())
+        .listener(TcpListener::from_std(listener)?)
+        .build()
+        .await?;
+    let handle = server.handle();
+    let running = tokio::spawn(server.run());
+    Client::connect(addr, "alice").await?;
+    handle.shutdown();
+    running.await??;
+
+    let probe = TcpListener::from_std(probe)?;
+    let _client = TcpStream::connect(addr).await?;
+    let (accepted, _) = probe.accept().await?;
+    assert!(socket2::SockRef::from(&accepted).keepalive()?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_certificates_name_tls_peers() -> Result<()> {
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/chat");
+    let mut config = test_config();
+    config.tls = Some(TlsConfig {
+        listen_addr: "unused".to_string(),
+        cert: fixtures.join("server.pem"),
+        key: fixtures.join("server.key"),
+        client_ca: Some(fixtures.join("ca.pem")),
+        require_client_cert: true,
+    });
+    let server = ChatServer::builder()
+        .config(config)
+        .listener(TcpListener::bind("127.0.0.1:0").await?)
+        .tls_listener(TcpListener::bind("127.0.0.1:0").await?)
+        .build()
+        .await?;
+    let addr = server.local_addr()?;
+    let tls_addr = server.tls_addr().expect("tls is configured");
+    tokio::spawn(server.run());
+    let mut bob = Client::connect(addr, "bob").await?;
+
+    let mut roots = RootCertStore::empty();
+    for cert in read_certs(&fixtures.join("ca.pem"))? {
+        roots.add(cert)?;
+    }
+    let key = rustls_pemfile::private_key(&mut &*std::fs::read(fixtures.join("alice.key"))?)?
+        .ok_or_else(|| anyhow!("alice.key has no private key"))?;
+    let tls = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_client_auth_cert(read_certs(&fixtures.join("alice.pem"))?, key)?;
+    let stream = TcpStream::connect(tls_addr).await?;
+    let stream = TlsConnector::from(Arc::new(tls))
+        .connect(ServerName::try_from("localhost")?, stream)
+        .await?;
+    let mut alice = Framed::new(stream, LinesCodec::new());
+
+    // the certificate names alice, so she joins without being asked for a username
+    bob.expect("[alice joined #lobby]").await?;
+    alice.send("hi bob").await?;
+    bob.expect("alice: hi bob").await?;
+    Ok(())
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let pem = std::fs::read(path)?;
+    Ok(rustls_pemfile::certs(&mut &*pem).collect::<Result<_, _>>()?)
+}
+
+#[tokio::test]
+async fn mute_durations_are_bounded() -> Result<()> {
+    let mut config = test_config();
+    config.moderation.secret = Some("hunter2".to_string());
+    let (addr, _handle) = start(config).await?;
+    let mut alice = Client::connect(addr, "alice").await?;
+    let mut bob = Client::connect(addr, "bob").await?;
+    alice.expect("[bob joined #lobby]").await?;
+    alice.send("/admin hunter2").await?;
+    alice.expect("* You are now an admin").await?;
+
+    alice.send("/mute bob 18446744073709551615").await?;
+    alice
+        .expect("! Mute duration must be at most 604800 seconds")
+        .await?;
+    alice.send("/mute bob 18446744073709551615h").await?;
+    alice
+        .expect("! Invalid duration: 18446744073709551615h")
+        .await?;
+    alice.send("/mute bob 2h").await?;
+    alice.expect("* Muted bob for 7200 seconds").await?;
+    bob.expect("! You were muted by alice for 7200 seconds")
+        .await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn mutes_outlast_a_reconnect() -> Result<()> {
+    let mut config = test_config();
+    config.moderation.secret = Some("hunter2".to_string());
+    let (addr, _handle) = start(config).await?;
+    let mut alice = Client::connect(addr, "alice").await?;
+    let mut bob = Client::connect(addr, "bob").await?;
+    alice.expect("[bob joined #lobby]").await?;
+    alice.send("/admin hunter2").await?;
+    alice.expect("* You are now an admin").await?;
+    alice.send("/mute bob 1h").await?;
+    alice.expect("* Muted bob for 3600 seconds").await?;
+    bob.expect("! You were muted by alice for 3600 seconds")
+        .await?;
+
+    bob.quit().await;
+    alice.expect("[bob left #lobby :(]").await?;
+    let mut bob = Client::connect(addr, "bob").await?;
+    alice.expect("[bob joined #lobby]").await?;
+    bob.send("I'm back").await?;
+    bob.expect_prefix("! You are muted for ").await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn bans_are_saved_and_survive_a_restart() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("chat-test-bans-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let mut config = test_config();
+    config.moderation.secret = Some("hunter2".to_string());
+    config.moderation.ban_file = Some(dir.join("bans.json"));
+    let (addr, _handle) = start(config).await?;
+    let mut alice = Client::connect(addr, "alice").await?;
+    alice.send("/admin hunter2").await?;
+    alice.expect("* You are now an admin").await?;
+    alice.send("/ban mallory").await?;
+    alice.expect("* Banned mallory").await?;
+
+    let mut config = test_config();
+    config.moderation.ban_file = Some(dir.join("bans.json"));
+    let (addr, _handle) = start(config).await?;
+    let mut mallory = Framed::new(TcpStream::connect(addr).await?, LinesCodec::new());
+    mallory.send("mallory").await?;
+    let mut lines = Vec::new();
+    while let Some(line) = tokio::time::timeout(TIMEOUT, mallory.next()).await? {
+        lines.push(line?);
+    }
+    assert_eq!(lines, ["Enter your username:", "! You are banned"]);
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn attachments_are_verified_and_relayed() -> Result<()> {
+    let mut config = test_config();
+    config.attachments.max_size = 64 * 1024;
+    config.attachments.chunk_size = 1024;
+    let framed = TcpListener::bind("127.0.0.1:0").await?;
+    let framed_addr = framed.local_addr()?;
+    let (addr, _handle) = start_with(config, Some(framed)).await?;
+    let mut alice = FramedClient::connect(framed_addr, "alice").await?;
+    let mut bob = FramedClient::connect(framed_addr, "bob").await?;
+    alice.expect_text("[bob joined #lobby]").await?;
+    let mut carol = Client::connect(addr, "carol").await?;
+    alice.expect_text("[carol joined #lobby]").await?;
+    bob.expect_text("[carol joined #lobby]").await?;
+
+    let data = Bytes::from((0..5000).map(|i| i as u8).collect::<Vec<_>>());
+    let checksum = blake3::hash(&data);
+    alice
+        .upload(7, "notes.bin", &data, *checksum.as_bytes())
+        .await?;
+    alice.expect(Frame::Ack { id: 7, error: None }).await?;
+
+    let announcement = format!("[alice shared notes.bin (5000 bytes, blake3 {})]", checksum);
+    carol.expect(&announcement).await?;
+    bob.expect_text(&announcement).await?;
+    let Frame::AttachmentStart {
+        id,
+        size,
+        checksum: received,
+        name,
+    } = bob.recv().await?
+    else {
+        return Err(anyhow!("expected the start of the attachment"));
+    };
+    assert_eq!(
+        (size, received, name.as_str()),
+        (5000, *checksum.as_bytes(), "notes.bin")
+    );
+    let mut content = Vec::new();
+    loop {
+        match bob.recv().await? {
+            Frame::AttachmentChunk { id: chunk_id, data } if chunk_id == id => {
+                assert!(data.len() <= 1024);
+                content.extend_from_slice(&data);
+            }
+            Frame::AttachmentEnd { id: end_id } if end_id == id => break,
+            frame => return Err(anyhow!("unexpected frame {:?}", frame)),
+        }
+    }
+    assert_eq!(content, data);
+
+    // a corrupted upload is rejected and never reaches the room
+    alice.upload(8, "bad.bin", &data, [0; 32]).await?;
+    alice
+        .expect(Frame::Ack {
+            id: 8,
+            error: Some("Checksum mismatch for attachment 8".to_string()),
+        })
+        .await?;
+    let too_big = Bytes::from(vec![0; 64 * 1024 + 1]);
+    let checksum = blake3::hash(&too_big);
+    alice
+        .upload(9, "big.bin", &too_big, *checksum.as_bytes())
+        .await?;
+    alice
+        .expect(Frame::Ack {
+            id: 9,
+            error: Some("Attachment exceeds 65536 bytes".to_string()),
+        })
+        .await?;
+    // the rest of the rejected upload is ignored
+    alice.send(Frame::Text("/rooms".to_string())).await?;
+    alice.expect_text("* Active rooms: #lobby (3)").await?;
+    alice.send(Frame::Text("done".to_string())).await?;
+    carol.expect("alice: done").await?;
+    bob.expect_text("alice: done").await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn attachments_fit_the_default_rate_limits() -> Result<()> {
+    // unlike `test_config`, keep the rate limits a real deployment has
+    let config = Config::default();
+    let framed = TcpListener::bind("127.0.0.1:0").await?;
+    let framed_addr = framed.local_addr()?;
+    let (_addr, _handle) = start_with(config, Some(framed)).await?;
+    let mut alice = FramedClient::connect(framed_addr, "alice").await?;
+
+    // a single chunk larger than the chat byte burst
+    let data = Bytes::from(vec![7; 16 * 1024]);
+    let checksum = blake3::hash(&data);
+    alice
+        .send(Frame::AttachmentStart {
+            id: 1,
+            size: data.len() as u64,
+            checksum: *checksum.as_bytes(),
+            name: "one.bin".to_string(),
+        })
+        .await?;
+    alice.send(Frame::AttachmentChunk { id: 1, data }).await?;
+    alice.send(Frame::AttachmentEnd { id: 1 }).await?;
+    alice.expect(Frame::Ack { id: 1, error: None }).await?;
+
+    // the largest upload allowed, in many small chunks
+    let data = Bytes::from((0..1024 * 1024).map(|i| i as u8).collect::<Vec<_>>());
+    let checksum = blake3::hash(&data);
+    alice
+        .upload(2, "big.bin", &data, *checksum.as_bytes())
+        .await?;
+    loop {
+        match alice.recv().await? {
+            Frame::Ack { id: 2, error } => {
+                assert_eq!(error, None);
+                break;
+            }
+            Frame::Text(_) => {}
+            frame => return Err(anyhow!("unexpected frame {:?}", frame)),
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn rooms_are_shared_across_a_cluster() -> Result<()> {
+    let links = [
+        TcpListener::bind("127.0.0.1:0").await?,
+        TcpListener::bind("127.0.0.1:0").await?,
+    ];
+    let link_addrs = [links[0].local_addr()?, links[1].local_addr()?];
+    let [link_a, link_b] = links;
+    let mut config = test_config();
+    config.cluster.peers = vec![link_addrs[1].to_string()];
+    let (a, a_handle) = start_node(config, link_a).await?;
+    let mut config = test_config();
+    config.cluster.peers = vec![link_addrs[0].to_string()];
+    let (b, b_handle) = start_node(config, link_b).await?;
+    tokio::time::timeout(TIMEOUT, async {
+        while a_handle.nodes().is_empty() || b_handle.nodes().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await?;
+
+    let mut alice = Client::connect(a, "alice").await?;
+    let mut bob = Client::connect(b, "bob").await?;
+    alice.expect("[bob joined #lobby]").await?;
+    bob.send("hi alice").await?;
+    alice.expect("bob: hi alice").await?;
+    alice.send("hi bob").await?;
+    bob.expect("alice: hi bob").await?;
+    // the copy relayed back to the origin node is dropped, not shown twice
+    bob.send("bye").await?;
+    alice.expect("bob: bye").await?;
+
+    let carol = Client::connect(b, "carol").await?;
+    assert_eq!(carol.history.len(), 3);
+    assert!(carol.history[1].ends_with("] alice: hi bob"));
+    alice.expect("[carol joined #lobby]").await?;
+
+    // every node numbers its peers from 1, relayed events carry no sender id
+    let mut dave = Framed::new(TcpStream::connect(a).await?, LinesCodec::new());
+    dave.send(r#"{"proto":"json"}"#).await?;
+    dave.send(r#"{"username":"dave"}"#).await?;
+    alice.expect("[dave joined #lobby]").await?;
+    bob.send("from b").await?;
+    alice.expect("bob: from b").await?;
+    alice.send("from a").await?;
+    let mut sender_ids = Vec::new();
+    while sender_ids.len() < 2 {
+        let line = tokio::time::timeout(TIMEOUT, dave.next())
+            .await?
+            .ok_or_else(|| anyhow!("dave was disconnected"))??;
+        let Ok(event) = serde_json::from_str::<serde_json::Value>(&line) else {
+            continue;
+        };
+        if event["type"] == "chat" && event["room"] == "lobby" {
+            sender_ids.push((event["sender"].clone(), event.get("sender_id").cloned()));
+        }
+    }
+    assert_eq!(sender_ids[0], ("bob".into(), None));
+    assert_eq!(sender_ids[1].0, "alice");
+    assert!(sender_ids[1].1.is_some());
+    Ok(())
+}
+
+#[tokio::test]
+async fn a_restarted_node_is_still_relayed() -> Result<()> {
+    let link_b = TcpListener::bind("127.0.0.1:0").await?;
+    let link_b_addr = link_b.local_addr()?;
+    let mut config = test_config();
+    // only accepts links from the host of the other node
+    config.cluster.peers = vec!["127.0.0.1:1".to_string()];
+    let (b, _b_handle) = start_node(config, link_b).await?;
+    let mut bob = Client::connect(b, "bob").await?;
+
+    // the restarted node numbers its messages from the start again
+    for run in 0..2 {
+        let mut config = test_config();
+        config.cluster.node_id = Some("a".to_string());
+        config.cluster.peers = vec![link_b_addr.to_string()];
+        let (a, a_handle) = start_node(config, TcpListener::bind("127.0.0.1:0").await?).await?;
+        tokio::time::timeout(TIMEOUT, async {
+            while a_handle.nodes().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        let mut alice = Client::connect(a, "alice").await?;
+        bob.expect("[alice joined #lobby]").await?;
+        alice.send(&format!("run {}", run)).await?;
+        bob.expect(&format!("alice: run {}", run)).await?;
+        alice.quit().await;
+        bob.expect("[alice left #lobby :(]").await?;
+        a_handle.shutdown();
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn metrics_count_peers_messages_and_fan_out() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let metrics = TcpListener::bind("127.0.0.1:0").await?;
+    let metrics_addr = metrics.local_addr()?;
+    let server = ChatServer::builder()
+        .config(test_config())
+        .listener(listener)
+        .metrics_listener(metrics)
+        .build()
+        .await?;
+    let addr = server.local_addr()?;
+    tokio::spawn(server.run());
+    let mut alice = Client::connect(addr, "alice").await?;
+    let mut bob = Client::connect(addr, "bob").await?;
+    alice.expect("[bob joined #lobby]").await?;
+    bob.send("hi").await?;
+    alice.expect("bob: hi").await?;
+
+    let mut stream = TcpStream::connect(metrics_addr).await?;
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    let lines: Vec<_> = response.lines().collect();
+    assert!(lines.contains(&"chat_peers 2"));
+    // a /rooms of each login and bob's message
+    assert!(lines.contains(&"chat_messages_received_total 3"));
+    assert!(lines.contains(&"chat_failed_sends_total 0"));
+    // both joins and the message were fanned out to the lobby
+    assert!(lines.contains(&"chat_broadcast_fan_out_seconds_count 3"));
+    let depths = lines
+        .iter()
+        .filter(|line| line.starts_with("chat_peer_queue_depth{"))
+        .count();
+    assert_eq!(depths, 2);
+    Ok(())
+}
+
+fn test_config() -> Config {
+    let mut config = Config::default();
+    // the scripted clients send much faster than a person would
+    config.rate_limit.messages_per_sec = 100_000.0;
+    config.rate_limit.message_burst = 100_000.0;
+    config.rate_limit.bytes_per_sec = 1e9;
+    config.rate_limit.byte_burst = 1e9;
+    config
+}
+
+async fn start(config: Config) -> Result<(SocketAddr, ChatHandle)> {
+    start_with(config, None).await
+}
+
+async fn start_with(
+    config: Config,
+    framed: Option<TcpListener>,
+) -> Result<(SocketAddr, ChatHandle)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let mut builder = ChatServer::builder().config(config).listener(listener);
+    if let Some(framed) = framed {
+        builder = builder.framed_listener(framed);
+    }
+    let server = builder.build().await?;
+    let addr = server.local_addr()?;
+    let handle = server.handle();
+    tokio::spawn(server.run());
+    Ok((addr, handle))
+}
+
+async fn start_node(config: Config, cluster: TcpListener) -> Result<(SocketAddr, ChatHandle)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let server = ChatServer::builder()
+        .config(config)
+        .listener(listener)
+        .cluster_listener(cluster)
+        .build()
+        .await?;
+    let addr = server.local_addr()?;
+    let handle = server.handle();
+    tokio::spawn(server.run());
+    Ok((addr, handle))
+}
+
+impl Client {
+    /// Log in and wait until the server has put the client into the lobby.
+    async fn connect(addr: SocketAddr, username: &str) -> Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = Self {
+            username: username.to_string(),
+            framed: Framed::new(stream, LinesCodec::new()),
+            history: Vec::new(),
+        };
+        client.expect("Enter your username:").await?;
+        client.send(username).await?;
+        // commands are only read after joining, so the answer proves we joined
+        client.history = client.sync().await?;
+        Ok(client)
+    }
+
+    /// Wait until the server has handled everything sent so far, returning
+    /// the lines received in the meantime.
+    async fn sync(&mut self) -> Result<Vec<String>> {
+        self.send("/rooms").await?;
+        let mut lines = Vec::new();
+        loop {
+            let line = self.recv().await?;
+            if line.starts_with("* Active rooms: ") {
+                return Ok(lines);
+            }
+            lines.push(line);
+        }
+    }
+
+    async fn send(&mut self, line: &str) -> Result<()> {
+        self.framed.send(line).await?;
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Result<String> {
+        match tokio::time::timeout(TIMEOUT, self.framed.next()).await {
+            Ok(Some(line)) => Ok(line?),
+            Ok(None) => Err(anyhow!("{} was disconnected", self.username)),
+            Err(_) => Err(anyhow!("{} timed out waiting for a line", self.username)),
+        }
+    }
+
+    async fn expect(&mut self, expected: &str) -> Result<()> {
+        let line = self.recv().await?;
+        assert_eq!(line, expected, "unexpected line for {}", self.username);
+        Ok(())
+    }
+
+    async fn expect_prefix(&mut self, prefix: &str) -> Result<()> {
+        let line = self.recv().await?;
+        assert!(
+            line.starts_with(prefix),
+            "{} got {:?}, expected {:?}...",
+            self.username,
+            line,
+            prefix
+        );
+        Ok(())
+    }
+
+    async fn quit(self) {
+        drop(self.framed);
+    }
+}
+
+impl FramedClient {
+    async fn connect(addr: SocketAddr, username: &str) -> Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = Self {
+            username: username.to_string(),
+            framed: Framed::new(stream, FrameCodec::new(1024 * 1024)),
+        };
+        client.expect_text("Enter your username:").await?;
+        client.send(Frame::Text(username.to_string())).await?;
+        client.send(Frame::Text("/rooms".to_string())).await?;
+        loop {
+            let Frame::Text(line) = client.recv().await? else {
+                continue;
+            };
+            if line.starts_with("* Active rooms: ") {
+                return Ok(client);
+            }
+        }
+    }
+
+    async fn upload(
+        &mut self,
+        id: u32,
+        name: &str,
+        data: &Bytes,
+        checksum: [u8; 32],
+    ) -> Result<()> {
+        self.send(Frame::AttachmentStart {
+            id,
+            size: data.len() as u64,
+            checksum,
+            name: name.to_string(),
+        })
+        .await?;
+        for chunk in data.chunks(1000) {
+            let data = Bytes::copy_from_slice(chunk);
+            self.send(Frame::AttachmentChunk { id, data }).await?;
+        }
+        self.send(Frame::AttachmentEnd { id }).await
+    }
+
+    async fn send(&mut self, frame: Frame) -> Result<()> {
+        self.framed.send(frame).await
+    }
+
+    async fn recv(&mut self) -> Result<Frame> {
+        match tokio::time::timeout(TIMEOUT, self.framed.next()).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => Err(anyhow!("{} was disconnected", self.username)),
+            Err(_) => Err(anyhow!("{} timed out waiting for a frame", self.username)),
+        }
+    }
+
+    async fn expect(&mut self, expected: Frame) -> Result<()> {
+        let frame = self.recv().await?;
+        assert_eq!(frame, expected, "unexpected frame for {}", self.username);
+        Ok(())
+    }
+
+    async fn expect_text(&mut self, expected: &str) -> Result<()> {
+        self.expect(Frame::Text(expected.to_string())).await
+    }
+}